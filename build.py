@@ -53,6 +53,11 @@ def make_bootcode():
 
 def make_kernel():
     cmd = f"RUST_TARGET_PATH=\"{SELFPATH}\" {_CARGO_BANARY} build --manifest-path \"{os.path.join(SELFPATH, 'Cargo.toml')}\" --target-dir \"{OUTPUTS}\" -r"
+    if "--heap-debug" in sys.argv:
+        # frame pointers are needed to record allocation call sites
+        cmd = f"RUSTFLAGS=\"-C force-frame-pointers=yes\" {cmd} --features heap-debug"
+    if "--lock-debug" in sys.argv:
+        cmd = f"{cmd} --features lock-debug"
     print(f"$ {cmd}")
     exit_code = os.system(cmd)
     try:
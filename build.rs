@@ -0,0 +1,20 @@
+// Sets the `frame_pointers` cfg when the kernel is built with `-C force-frame-pointers`,
+// the heap debugging code only walks the stack then.
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(frame_pointers)");
+    println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let mut previous = "";
+    for flag in flags.split('\x1f') {
+        let codegen = if previous == "-C" { flag } else { flag.strip_prefix("-C").unwrap_or("") };
+        if let Some(value) = codegen.strip_prefix("force-frame-pointers") {
+            if ["", "=yes", "=y", "=on", "=true"].contains(&value) {
+                println!("cargo:rustc-cfg=frame_pointers");
+            }
+        }
+        previous = flag;
+    }
+}
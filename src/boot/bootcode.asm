@@ -1,10 +1,16 @@
 global start
+global stack_top
+global gdt64_high_pointer
 extern long_mode_start
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000
+
+; The boot code runs at its physical load address, everything else is linked in the higher half,
+; so all symbols outside of this section have to be translated with KERNEL_OFFSET before paging is on
+section .boot progbits alloc exec nowrite align=16
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
     mov edi, ebx
 
     call check_multiboot
@@ -14,9 +20,9 @@ start:
     call set_up_page_tables
     call enable_paging
 
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.pointer - KERNEL_OFFSET]
 
-    jmp gdt64.code:long_mode_start
+    jmp gdt64.code:higher_half_trampoline
 
 check_multiboot:
     cmp eax, 0x36d76289
@@ -69,18 +75,23 @@ check_long_mode:
     jmp error
 
 set_up_page_tables:
-    ; map P4 table recursively
-    mov eax, p4_table
+    ; identity map the first GiB, needed until we jump to the higher half
+    mov eax, p3_table - KERNEL_OFFSET
     or eax, 0b11
-    mov [p4_table + 511 * 8], eax
+    mov [p4_table - KERNEL_OFFSET], eax
+
+    ; the same GiB is the start of the physical memory map (P4 entry 256)
+    mov [p4_table - KERNEL_OFFSET + 256 * 8], eax
 
-    mov eax, p3_table
+    ; and it is mapped at KERNEL_OFFSET (P4 entry 511, P3 entry 510)
+    mov eax, p3_high_table - KERNEL_OFFSET
     or eax, 0b11
-    mov [p4_table], eax
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
 
-    mov eax, p2_table
+    mov eax, p2_table - KERNEL_OFFSET
     or eax, 0b11
-    mov [p3_table], eax
+    mov [p3_table - KERNEL_OFFSET], eax
+    mov [p3_high_table - KERNEL_OFFSET + 510 * 8], eax
 
     mov ecx, 0
 
@@ -88,7 +99,7 @@ set_up_page_tables:
     mov eax, 0x200000
     mul ecx
     or eax, 0b10000011
-    mov [p2_table + ecx * 8], eax
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax
 
     inc ecx
     cmp ecx, 512
@@ -98,7 +109,7 @@ set_up_page_tables:
 
 enable_paging:
     ; load P4 to cr3 register
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE-flag in cr4
@@ -127,14 +138,22 @@ error:
     mov byte  [0xb800a], al
     hlt
 
+bits 64
+higher_half_trampoline:
+    mov rax, long_mode_start
+    jmp rax
+
 section .bss
 align 4096
-p4_table:
-    resb 4096
 p3_table:
     resb 4096
+p3_high_table:
+    resb 4096
 p2_table:
     resb 4096
+; unmapped after the kernel is remapped, so it becomes the guard page of the boot stack
+p4_table:
+    resb 4096
 stack_bottom:
     resb 4096 * 4
 stack_top:
@@ -146,4 +165,7 @@ gdt64:
     dq (1<<43) | (1<<44) | (1<<47) | (1<<53)
 .pointer:
     dw $ - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET
+gdt64_high_pointer:
+    dw gdt64.pointer - gdt64 - 1
     dq gdt64
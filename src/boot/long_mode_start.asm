@@ -1,8 +1,14 @@
 global long_mode_start
+extern stack_top
+extern gdt64_high_pointer
 
 section .text
 bits 64
 long_mode_start:
+    ; reload the GDT and the stack using their higher half addresses
+    lgdt [gdt64_high_pointer]
+    mov rsp, stack_top
+
     ; load 0 into all data segment registers
     mov ax, 0
     mov ss, ax
@@ -0,0 +1,54 @@
+// Just enough AML to get the sleep types out of the \_S5 package, there is no interpreter.
+// The package is nearly always a static `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, .. })`.
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_CHAR: u8 = b'\\';
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+
+fn integer(data: &[u8], position: &mut usize) -> Option<u32> {
+    let op = *data.get(*position)?;
+    *position += 1;
+
+    let size = match op {
+        ZERO_OP => return Some(0),
+        ONE_OP => return Some(1),
+        BYTE_PREFIX => 1,
+        WORD_PREFIX => 2,
+        DWORD_PREFIX => 4,
+        _ => return None,
+    };
+
+    let bytes = data.get(*position..*position + size)?;
+    *position += size;
+    return Some(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u32));
+}
+
+// (SLP_TYPa, SLP_TYPb) for the soft-off state
+pub fn s5_sleep_types(aml: &[u8]) -> Option<(u16, u16)> {
+    let name = aml.windows(4).enumerate()
+        .filter(|(_, window)| *window == b"_S5_")
+        .map(|(index, _)| index)
+        .find(|&index| {
+            index >= 1 && (aml[index - 1] == NAME_OP || (index >= 2 && aml[index - 1] == ROOT_CHAR && aml[index - 2] == NAME_OP))
+        })?;
+
+    let mut position = name + 4;
+    if *aml.get(position)? != PACKAGE_OP {
+        return None;
+    }
+    position += 1;
+
+    // PkgLength, bits 6-7 of the lead byte are the number of bytes that follow it
+    position += 1 + (*aml.get(position)? >> 6) as usize;
+    // NumElements
+    position += 1;
+
+    let sleep_type_a = integer(aml, &mut position)?;
+    let sleep_type_b = integer(aml, &mut position)?;
+    return Some((sleep_type_a as u16, sleep_type_b as u16));
+}
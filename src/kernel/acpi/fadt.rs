@@ -0,0 +1,88 @@
+// Fixed ACPI Description Table ("FACP"), the power management registers and the DSDT address.
+// Fields are read only if the table is long enough, the extended (X_) ones win over the legacy ones.
+use crate::memory::PhysicalAddress;
+use super::{GenericAddress, read_u16, read_u32, read_u64};
+
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysicalAddress,
+    pub sci_interrupt: u16,
+    // writing `acpi_enable` there switches the firmware from legacy to ACPI mode, 0 if there is no SMM
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<GenericAddress>,
+    pub pm1b_event_block: Option<GenericAddress>,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm_timer_block: Option<GenericAddress>,
+    pub century: u8,
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    // flags
+    pub const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+    pub const HARDWARE_REDUCED: u32 = 1 << 20;
+
+    // IA-PC boot architecture flags
+    pub const LEGACY_DEVICES: u16 = 1 << 0;
+    pub const HAS_8042: u16 = 1 << 1;
+
+    pub fn parse(data: &[u8]) -> Fadt {
+        let length = data.len();
+        let u8_at = |offset: usize| if offset < length { data[offset] } else { 0 };
+        let u16_at = |offset: usize| if offset + 2 <= length { read_u16(data, offset) } else { 0 };
+        let u32_at = |offset: usize| if offset + 4 <= length { read_u32(data, offset) } else { 0 };
+
+        // legacy I/O port block (port, length) and the offset of its extended version
+        let block = |port_offset: usize, length_offset: usize, extended_offset: usize| -> Option<GenericAddress> {
+            if extended_offset + 12 <= length {
+                let extended = GenericAddress::parse(data, extended_offset);
+                if extended.address != 0 {
+                    return Some(extended);
+                }
+            }
+            return match u32_at(port_offset) {
+                0 => None,
+                port => Some(GenericAddress::io_port(port, u8_at(length_offset))),
+            };
+        };
+
+        let flags = u32_at(112);
+
+        let mut dsdt = u32_at(40) as usize;
+        if length >= 148 && read_u64(data, 140) != 0 {
+            dsdt = read_u64(data, 140) as usize;
+        }
+
+        let reset_register = if flags & Fadt::RESET_REGISTER_SUPPORTED != 0 && length >= 129 {
+            Some(GenericAddress::parse(data, 116))
+        } else {
+            None
+        };
+
+        return Fadt {
+            revision: data[8],
+            dsdt: dsdt,
+            sci_interrupt: u16_at(46),
+            smi_command_port: u32_at(48),
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_event_block: block(56, 88, 148),
+            pm1b_event_block: block(60, 88, 160),
+            pm1a_control_block: block(64, 89, 172),
+            pm1b_control_block: block(68, 89, 184),
+            pm_timer_block: block(76, 91, 208),
+            century: u8_at(108),
+            boot_architecture: u16_at(109),
+            flags: flags,
+            reset_register: reset_register,
+            reset_value: u8_at(128),
+        };
+    }
+}
@@ -0,0 +1,40 @@
+// High Precision Event Timer description ("HPET")
+use super::{GenericAddress, read_u16, read_u32};
+
+const TABLE_SIZE: usize = 56;
+
+#[derive(Debug, Clone)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_is_64bit: bool,
+    // the HPET can replace the PIT and the RTC interrupts
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub address: GenericAddress,
+    pub number: u8,
+    // in periodic mode, in main counter ticks
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    // Returns None if the table is too short, it has no optional fields
+    pub fn parse(data: &[u8]) -> Option<Hpet> {
+        if data.len() < TABLE_SIZE {
+            return None;
+        }
+
+        let block_id = read_u32(data, 36);
+
+        return Some(Hpet {
+            hardware_revision: block_id as u8,
+            comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_is_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+            address: GenericAddress::parse(data, 40),
+            number: data[52],
+            minimum_tick: read_u16(data, 53),
+        });
+    }
+}
@@ -0,0 +1,159 @@
+// Multiple APIC Description Table ("APIC"), lists the local APICs, I/O APICs and how ISA IRQs are wired
+use alloc::vec::Vec;
+
+use crate::interrupts::ioapic::{Polarity, Trigger};
+use crate::memory::PhysicalAddress;
+use super::{read_u16, read_u32, read_u64};
+
+// the header is followed by the local APIC address and the flags
+const ENTRIES_OFFSET: usize = 44;
+
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    // disabled processors which are online capable can still be started
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysicalAddress,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // None for all processors
+    pub processor_id: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysicalAddress,
+    // there are 8259 PICs which have to be masked when the APICs are used
+    pub has_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+// MPS INTI flags, "conforms to the bus" means active high and edge triggered for ISA
+fn inti_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh };
+    let trigger = if (flags >> 2) & 0b11 == 0b11 { Trigger::Level } else { Trigger::Edge };
+    return (polarity, trigger);
+}
+
+impl Madt {
+    // Returns None if the table is too short for the fixed fields
+    pub fn parse(data: &[u8]) -> Option<Madt> {
+        if data.len() < ENTRIES_OFFSET {
+            return None;
+        }
+
+        let mut madt = Madt {
+            local_apic_address: read_u32(data, 36) as usize,
+            has_pics: read_u32(data, 40) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 2 <= data.len() {
+            let typ = data[offset];
+            let length = data[offset + 1] as usize;
+            if length < 2 || offset + length > data.len() {
+                break;
+            }
+            let entry = &data[offset..offset + length];
+
+            match typ {
+                LOCAL_APIC if length >= 8 => {
+                    let flags = read_u32(entry, 4);
+                    madt.processors.push(Processor {
+                        processor_id: entry[2] as u32,
+                        apic_id: entry[3] as u32,
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                IO_APIC if length >= 12 => {
+                    madt.io_apics.push(IoApic {
+                        id: entry[2],
+                        address: read_u32(entry, 4) as usize,
+                        gsi_base: read_u32(entry, 8),
+                    });
+                }
+                INTERRUPT_OVERRIDE if length >= 10 => {
+                    let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                    madt.overrides.push(InterruptOverride {
+                        irq: entry[3],
+                        gsi: read_u32(entry, 4),
+                        polarity: polarity,
+                        trigger: trigger,
+                    });
+                }
+                LOCAL_APIC_NMI if length >= 6 => {
+                    let (polarity, trigger) = inti_flags(read_u16(entry, 3));
+                    madt.nmis.push(LocalApicNmi {
+                        processor_id: if entry[2] == 0xff { None } else { Some(entry[2] as u32) },
+                        lint: entry[5],
+                        polarity: polarity,
+                        trigger: trigger,
+                    });
+                }
+                LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                    madt.local_apic_address = read_u64(entry, 4) as usize;
+                }
+                LOCAL_X2APIC if length >= 16 => {
+                    let flags = read_u32(entry, 8);
+                    madt.processors.push(Processor {
+                        processor_id: read_u32(entry, 12),
+                        apic_id: read_u32(entry, 4),
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        return Some(madt);
+    }
+
+    // Where the ISA IRQ is connected, without an override it is the GSI with the same number
+    pub fn isa_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        return self.overrides.iter().find(|isa_override| isa_override.irq == irq);
+    }
+}
@@ -0,0 +1,55 @@
+// PCI Express memory mapped configuration space ("MCFG"), one ECAM window per segment group
+use alloc::vec::Vec;
+
+use crate::memory::PhysicalAddress;
+use super::{read_u16, read_u64};
+
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciSegment {
+    pub base_address: PhysicalAddress,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl PciSegment {
+    // Physical address of the 4 KiB configuration space of the function
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysicalAddress> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as usize) << 20 | (device as usize) << 15 | (function as usize) << 12;
+        return Some(self.base_address + offset);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub segments: Vec<PciSegment>,
+}
+
+impl Mcfg {
+    pub fn parse(data: &[u8]) -> Mcfg {
+        let mut segments = Vec::new();
+
+        // 8 reserved bytes follow the header
+        let mut offset = 44;
+        while offset + ENTRY_SIZE <= data.len() {
+            segments.push(PciSegment {
+                base_address: read_u64(data, offset) as usize,
+                segment: read_u16(data, offset + 8),
+                start_bus: data[offset + 10],
+                end_bus: data[offset + 11],
+            });
+            offset += ENTRY_SIZE;
+        }
+
+        return Mcfg { segments: segments };
+    }
+
+    pub fn segment(&self, segment: u16, bus: u8) -> Option<&PciSegment> {
+        return self.segments.iter().find(|entry| entry.segment == segment && bus >= entry.start_bus && bus <= entry.end_bus);
+    }
+}
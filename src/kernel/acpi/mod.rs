@@ -0,0 +1,387 @@
+// ACPI tables. The RSDP is taken from the multiboot2 tags (or found in the BIOS areas), every table
+// listed by the RSDT/XSDT is checksummed and mapped, and the ones the kernel needs are parsed into
+// plain structures, so the interrupt, timer and PCI code never look at raw tables.
+use alloc::vec::Vec;
+use multiboot2::BootInformation;
+use spin::Once;
+
+use crate::memory::{self, EntryFlags, PhysicalAddress, phys_to_virt};
+
+mod madt;
+mod fadt;
+mod hpet;
+mod mcfg;
+mod aml;
+
+pub use self::madt::Madt;
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+pub use self::mcfg::Mcfg;
+
+const RSDP_V1_TAG: u32 = 14;
+const RSDP_V2_TAG: u32 = 15;
+const END_TAG: u32 = 0;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+const HEADER_SIZE: usize = 36;
+// anything bigger is a corrupted header, the DSDT is the biggest table and stays far below
+const MAX_TABLE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+// ACPI Generic Address Structure, describes a register in memory or I/O space
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(data: &[u8], offset: usize) -> GenericAddress {
+        let address_space = match data[offset] {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        return GenericAddress {
+            address_space: address_space,
+            bit_width: data[offset + 1],
+            bit_offset: data[offset + 2],
+            access_size: data[offset + 3],
+            address: read_u64(data, offset + 4),
+        };
+    }
+
+    // Registers in memory and I/O space, and bytes in the PCI configuration space of bus 0
+    pub fn read(&self) -> Option<u64> {
+        return self.access(None);
+    }
+
+    // Returns false if the register cannot be accessed
+    pub fn write(&self, value: u64) -> bool {
+        return self.access(Some(value)).is_some();
+    }
+
+    fn access(&self, value: Option<u64>) -> Option<u64> {
+        use x86_64::instructions::port::Port;
+
+        let width = if self.bit_width == 0 { 8 } else { self.bit_width };
+        match self.address_space {
+            AddressSpace::SystemIo => unsafe {
+                let port = self.address as u16;
+                return match (width, value) {
+                    (8, None) => Some(Port::<u8>::new(port).read() as u64),
+                    (16, None) => Some(Port::<u16>::new(port).read() as u64),
+                    (32, None) => Some(Port::<u32>::new(port).read() as u64),
+                    (8, Some(value)) => { Port::<u8>::new(port).write(value as u8); Some(value) }
+                    (16, Some(value)) => { Port::<u16>::new(port).write(value as u16); Some(value) }
+                    (32, Some(value)) => { Port::<u32>::new(port).write(value as u32); Some(value) }
+                    _ => None,
+                };
+            },
+            AddressSpace::SystemMemory => {
+                let size = width as usize / 8;
+                let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+                // also used on the panic path, where the memory controller may be locked
+                let mut controller = memory::try_controller()?;
+                let address = controller.map_physical(self.address as usize, size, flags)?;
+                let result = unsafe {
+                    match (width, value) {
+                        (8, None) => Some(core::ptr::read_volatile(address as *const u8) as u64),
+                        (16, None) => Some(core::ptr::read_volatile(address as *const u16) as u64),
+                        (32, None) => Some(core::ptr::read_volatile(address as *const u32) as u64),
+                        (64, None) => Some(core::ptr::read_volatile(address as *const u64)),
+                        (8, Some(value)) => { core::ptr::write_volatile(address as *mut u8, value as u8); Some(value) }
+                        (16, Some(value)) => { core::ptr::write_volatile(address as *mut u16, value as u16); Some(value) }
+                        (32, Some(value)) => { core::ptr::write_volatile(address as *mut u32, value as u32); Some(value) }
+                        (64, Some(value)) => { core::ptr::write_volatile(address as *mut u64, value); Some(value) }
+                        _ => None,
+                    }
+                };
+                controller.unmap_physical(address, size);
+                return result;
+            }
+            AddressSpace::PciConfig if width == 8 => {
+                // device in bits 32-47, function in bits 16-31, register offset in bits 0-15
+                let device = (self.address >> 32) as u32 & 0x1f;
+                let function = (self.address >> 16) as u32 & 0x7;
+                let offset = self.address as u32 & 0xff;
+
+                let mut config_address = Port::<u32>::new(0xcf8);
+                let mut config_data = Port::<u8>::new(0xcfc + (offset & 3) as u16);
+                unsafe {
+                    config_address.write(0x8000_0000 | device << 11 | function << 8 | (offset & 0xfc));
+                    return match value {
+                        None => Some(config_data.read() as u64),
+                        Some(value) => { config_data.write(value as u8); Some(value) }
+                    };
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    fn io_port(port: u32, length: u8) -> GenericAddress {
+        return GenericAddress {
+            address_space: AddressSpace::SystemIo,
+            bit_width: length * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub signature: [u8; 4],
+    pub physical_address: PhysicalAddress,
+    pub revision: u8,
+    // the whole table, header included
+    pub data: &'static [u8],
+}
+
+impl Table {
+    pub fn signature(&self) -> &str {
+        return core::str::from_utf8(&self.signature).unwrap_or("????");
+    }
+}
+
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    tables: Vec<Table>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Option<Mcfg>,
+    s5_sleep_types: Option<(u16, u16)>,
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes([data[offset], data[offset + 1]]);
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    return u32::from_le_bytes(bytes);
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    return u64::from_le_bytes(bytes);
+}
+
+fn checksum_is_valid(data: &[u8]) -> bool {
+    return data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0;
+}
+
+// Tables live in firmware reserved memory, which is not necessarily covered by the physical memory map
+fn map(start: PhysicalAddress, size: usize) -> &'static [u8] {
+    let address = memory::controller().map_physical(start, size, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE)
+        .expect("no virtual space left for ACPI tables");
+    return unsafe { core::slice::from_raw_parts(address as *const u8, size) };
+}
+
+fn unmap(data: &'static [u8]) {
+    memory::controller().unmap_physical(data.as_ptr() as usize, data.len());
+}
+
+fn map_table(start: PhysicalAddress) -> Option<Table> {
+    // the header is mapped on its own only to find the length
+    let header = map(start, HEADER_SIZE);
+    let length = read_u32(header, 4) as usize;
+    unmap(header);
+    if length < HEADER_SIZE || length > MAX_TABLE_SIZE {
+        return None;
+    }
+
+    let data = map(start, length);
+    if !checksum_is_valid(data) {
+        unmap(data);
+        return None;
+    }
+
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&data[0..4]);
+    return Some(Table {
+        signature: signature,
+        physical_address: start,
+        revision: data[8],
+        data: data,
+    });
+}
+
+fn rsdp_is_valid(rsdp: &[u8]) -> bool {
+    if rsdp.len() < RSDP_V1_SIZE || &rsdp[0..8] != RSDP_SIGNATURE || !checksum_is_valid(&rsdp[..RSDP_V1_SIZE]) {
+        return false;
+    }
+    // the XSDT address and the extended checksum follow from revision 2 on
+    if rsdp[15] >= 2 {
+        if rsdp.len() < RSDP_V2_SIZE {
+            return false;
+        }
+        let length = read_u32(rsdp, 20) as usize;
+        return length >= RSDP_V2_SIZE && length <= rsdp.len() && checksum_is_valid(&rsdp[..length]);
+    }
+    return true;
+}
+
+// Copy of the RSDP made by the bootloader, the newer tag is preferred
+fn find_rsdp_in_tags(boot_info: &BootInformation) -> Option<&'static [u8]> {
+    let mut found: Option<&'static [u8]> = None;
+
+    unsafe {
+        let mut tag = boot_info.start_address() + 8;
+        while tag < boot_info.end_address() {
+            let typ = *(tag as *const u32);
+            let size = *((tag + 4) as *const u32) as usize;
+            if typ == END_TAG {
+                break;
+            }
+
+            if typ == RSDP_V2_TAG || (typ == RSDP_V1_TAG && found.is_none()) {
+                let rsdp = core::slice::from_raw_parts((tag + 8) as *const u8, size - 8);
+                if rsdp_is_valid(rsdp) {
+                    found = Some(rsdp);
+                }
+            }
+
+            // tags are 8 byte aligned
+            tag += (size + 7) & !7;
+        }
+    }
+
+    return found;
+}
+
+// The RSDP is on a 16 byte boundary in the first KiB of the EBDA or in the BIOS ROM area
+fn find_rsdp_in_bios() -> Option<&'static [u8]> {
+    let ebda = unsafe { *(phys_to_virt(0x40e) as *const u16) as usize } << 4;
+
+    let mut areas = [(0xe0000, 0x100000), (0, 0)];
+    if ebda >= 0x80000 && ebda < 0xa0000 {
+        areas[1] = (ebda, ebda + 1024);
+    }
+
+    for &(start, end) in areas.iter().rev() {
+        for address in (start..end).step_by(16) {
+            let candidate = unsafe { core::slice::from_raw_parts(phys_to_virt(address) as *const u8, RSDP_V2_SIZE) };
+            if rsdp_is_valid(candidate) {
+                return Some(candidate);
+            }
+        }
+    }
+
+    return None;
+}
+
+// Returns false if there is no (valid) RSDP
+pub fn init(boot_info: &BootInformation) -> bool {
+    assert_has_not_been_called!("acpi::init can be called only once");
+
+    let rsdp = match find_rsdp_in_tags(boot_info).or_else(find_rsdp_in_bios) {
+        Some(rsdp) => rsdp,
+        None => return false,
+    };
+
+    let revision = rsdp[15];
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // the XSDT has 64 bit entries and replaces the RSDT since ACPI 2.0, `rsdp_is_valid` checked the size
+    let xsdt_address = if revision >= 2 { read_u64(rsdp, 24) as usize } else { 0 };
+    let (root, entry_size) = if xsdt_address != 0 {
+        (map_table(xsdt_address), 8)
+    } else {
+        (map_table(read_u32(rsdp, 16) as usize), 4)
+    };
+    let root = match root {
+        Some(root) => root,
+        None => return false,
+    };
+
+    let mut tables = Vec::new();
+    for offset in (HEADER_SIZE..root.data.len() - root.data.len() % entry_size).step_by(entry_size) {
+        let address = if entry_size == 8 { read_u64(root.data, offset) as usize } else { read_u32(root.data, offset) as usize };
+        if let Some(table) = map_table(address) {
+            tables.push(table);
+        }
+    }
+
+    let find = |signature: &[u8; 4]| tables.iter().find(|table| &table.signature == signature).copied();
+    let madt = find(b"APIC").and_then(|table| Madt::parse(table.data));
+    let fadt = find(b"FACP").map(|table| Fadt::parse(table.data));
+    let hpet = find(b"HPET").and_then(|table| Hpet::parse(table.data));
+    let mcfg = find(b"MCFG").map(|table| Mcfg::parse(table.data));
+
+    // the DSDT is only referenced by the FADT
+    let mut s5_sleep_types = None;
+    if let Some(dsdt) = fadt.as_ref().and_then(|fadt| map_table(fadt.dsdt)) {
+        s5_sleep_types = aml::s5_sleep_types(&dsdt.data[HEADER_SIZE..]);
+        tables.push(dsdt);
+    }
+
+    ACPI.call_once(|| Acpi {
+        revision: revision,
+        oem_id: oem_id,
+        tables: tables,
+        madt: madt,
+        fadt: fadt,
+        hpet: hpet,
+        mcfg: mcfg,
+        s5_sleep_types: s5_sleep_types,
+    });
+
+    return true;
+}
+
+pub fn get() -> Option<&'static Acpi> {
+    return ACPI.get();
+}
+
+// All checksummed tables, the DSDT included
+pub fn tables() -> impl Iterator<Item = &'static Table> {
+    return get().into_iter().flat_map(|acpi| acpi.tables.iter());
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static Table> {
+    return tables().find(|table| &table.signature == signature);
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    return get()?.madt.as_ref();
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    return get()?.fadt.as_ref();
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    return get()?.hpet.as_ref();
+}
+
+pub fn mcfg() -> Option<&'static Mcfg> {
+    return get()?.mcfg.as_ref();
+}
+
+// (SLP_TYPa, SLP_TYPb) values for soft-off, from the \_S5 object of the DSDT
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    return get()?.s5_sleep_types;
+}
@@ -0,0 +1,30 @@
+// Kernel command line options, given as space separated `flag` or `key=value` tokens
+use spin::Once;
+
+static COMMAND_LINE: Once<&'static str> = Once::new();
+
+pub fn init(command_line: &'static str) {
+    COMMAND_LINE.call_once(|| command_line.trim());
+}
+
+pub fn get() -> &'static str {
+    return COMMAND_LINE.get().map_or("", |command_line| *command_line);
+}
+
+pub fn flag(name: &str) -> bool {
+    return get().split_whitespace().any(|token| token == name);
+}
+
+pub fn value(name: &str) -> Option<&'static str> {
+    return get().split_whitespace().find_map(|token| {
+        let mut parts = token.splitn(2, '=');
+        if parts.next() == Some(name) {
+            return parts.next();
+        }
+        return None;
+    });
+}
+
+pub fn value_usize(name: &str) -> Option<usize> {
+    return value(name).and_then(|value| value.parse().ok());
+}
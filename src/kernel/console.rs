@@ -5,10 +5,11 @@ use crate::drivers::{vga_textmode};
 
 use alloc::vec::Vec;
 use core::fmt;
-use spin::Mutex;
 use lazy_static::lazy_static;
 use pc_keyboard::{DecodedKey, KeyCode};
 
+use crate::sync::IrqSpinLock;
+
 // TODO: More color modes (eg. Color8, Color255)
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -215,11 +216,7 @@ impl Console {
 
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        use x86_64::instructions::interrupts;
-
-        interrupts::without_interrupts(|| {
-            self.print(s);
-        });
+        self.print(s);
         Ok(())
     }
 }
@@ -231,13 +228,13 @@ pub fn _console_print(con: &mut Console, args: fmt::Arguments) {
 }
 
 pub struct Consoles {
-    pub con_list: Mutex<Vec<Console>>,
+    pub con_list: IrqSpinLock<Vec<Console>>,
 }
 
 impl Consoles {
     pub fn new() -> Consoles {
         return Consoles {
-            con_list: Mutex::new(Vec::new()),
+            con_list: IrqSpinLock::new("consoles", Vec::new()),
         };
     }
 
@@ -288,8 +285,8 @@ macro_rules! print_all {
 
 #[macro_export]
 macro_rules! println_all {
-    () => (print_all!("\n"));
-    ($( $arg:tt )*) => (print_all!("{}\n", format_args!($($arg)*)));
+    () => ($crate::print_all!("\n"));
+    ($( $arg:tt )*) => ($crate::print_all!("{}\n", format_args!($($arg)*)));
 }
 
 pub fn register_input(key: DecodedKey) {
@@ -92,3 +92,18 @@ pub fn get_processor_info() -> Processor {
         hypervisor: cpu_hypervisor,
     };
 }
+
+pub fn has_1gib_pages() -> bool {
+    let cpuid = cpuid::CpuId::new();
+    return cpuid.get_extended_processor_and_feature_identifiers().map_or(false, |info| info.has_1gib_pages());
+}
+
+pub fn has_rdrand() -> bool {
+    let cpuid = cpuid::CpuId::new();
+    return cpuid.get_feature_info().map_or(false, |info| info.has_rdrand());
+}
+
+pub fn has_rdseed() -> bool {
+    let cpuid = cpuid::CpuId::new();
+    return cpuid.get_extended_feature_info().map_or(false, |info| info.has_rdseed());
+}
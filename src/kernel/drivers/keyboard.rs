@@ -0,0 +1,119 @@
+// PS/2 keyboard. The IRQ handler only reads the scancode, a tasklet decodes it and handles
+// Ctrl+Alt+Del (reboot) and Ctrl+Alt+End (power off), and the keys go to the console from thread context.
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode, KeyState};
+use x86_64::instructions::port::Port;
+
+use crate::console;
+use crate::interrupts::deferred::{Tasklet, Work};
+use crate::interrupts::irq;
+use crate::power;
+use crate::sync::{IrqSpinLock, SpinLock};
+
+const IRQ_LINE: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+const BUFFER_SIZE: usize = 64;
+
+// Drops new entries when full, like the controller does when nobody reads it
+struct Ring<T: Copy> {
+    items: [Option<T>; BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy> Ring<T> {
+    const fn new() -> Ring<T> {
+        return Ring {
+            items: [None; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        };
+    }
+
+    fn push(&mut self, item: T) {
+        if self.len < BUFFER_SIZE {
+            self.items[(self.head + self.len) % BUFFER_SIZE] = Some(item);
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        return item;
+    }
+}
+
+lazy_static! {
+    // only used by the decode tasklet
+    static ref KEYBOARD: SpinLock<Keyboard<layouts::Us104Key, ScancodeSet1>> = SpinLock::new(
+        "keyboard decoder", Keyboard::new(HandleControl::Ignore)
+    );
+}
+// (control, alt) held, the decoder does not expose its modifier state
+static MODIFIERS: SpinLock<(bool, bool)> = SpinLock::new("keyboard modifiers", (false, false));
+
+static SCANCODES: IrqSpinLock<Ring<u8>> = IrqSpinLock::new("keyboard scancodes", Ring::new());
+static KEYS: IrqSpinLock<Ring<DecodedKey>> = IrqSpinLock::new("keyboard keys", Ring::new());
+
+static DECODE: Tasklet = Tasklet::new(decode_scancodes);
+static DELIVER: Work = Work::new(deliver_keys);
+
+pub fn init() {
+    if !irq::register_irq(IRQ_LINE, keyboard_interrupt, "keyboard") {
+        panic!("could not register the keyboard interrupt");
+    }
+}
+
+fn keyboard_interrupt(_line: u8) -> bool {
+    let scancode: u8 = unsafe { Port::new(DATA_PORT).read() };
+    SCANCODES.lock().push(scancode);
+    DECODE.schedule();
+    return true;
+}
+
+fn decode_scancodes() {
+    let mut keyboard = KEYBOARD.lock();
+
+    loop {
+        let scancode = match SCANCODES.lock().pop() {
+            Some(scancode) => scancode,
+            None => break,
+        };
+        let key_event = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => key_event,
+            _ => continue,
+        };
+
+        let mut modifiers = MODIFIERS.lock();
+        let pressed = key_event.state == KeyState::Down;
+        match key_event.code {
+            KeyCode::ControlLeft | KeyCode::ControlRight => modifiers.0 = pressed,
+            KeyCode::AltLeft | KeyCode::AltRight => modifiers.1 = pressed,
+            KeyCode::Delete if pressed && modifiers.0 && modifiers.1 => power::reboot(),
+            KeyCode::End if pressed && modifiers.0 && modifiers.1 => power::power_off(),
+            _ => {}
+        }
+        drop(modifiers);
+
+        if let Some(key) = keyboard.process_keyevent(key_event) {
+            KEYS.lock().push(key);
+            DELIVER.queue();
+        }
+    }
+}
+
+// The console draws to the screen under its own lock, which is not safe at interrupt time
+fn deliver_keys() {
+    loop {
+        let key = match KEYS.lock().pop() {
+            Some(key) => key,
+            None => break,
+        };
+        console::register_input(key);
+    }
+}
@@ -2,6 +2,10 @@
 
 // [CPU]
 pub mod cpuid;
+pub mod random;
+
+// [INPUT]
+pub mod keyboard;
 
 // [GRAPHICS]
 pub mod vga_textmode;
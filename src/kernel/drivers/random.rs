@@ -0,0 +1,67 @@
+// Seeds from the CPU random number generators. Good enough for things like KASLR,
+// but there is no entropy pool behind it yet.
+use core::arch::asm;
+
+use crate::drivers::cpuid;
+
+const RETRIES: usize = 10;
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    return None;
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    return None;
+}
+
+// RDSEED, then RDRAND, and the time stamp counter as the last resort
+pub fn hardware_seed() -> u64 {
+    if cpuid::has_rdseed() {
+        if let Some(seed) = rdseed() {
+            return seed;
+        }
+    }
+    if cpuid::has_rdrand() {
+        if let Some(seed) = rdrand() {
+            return seed;
+        }
+    }
+
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    return SplitMix64::new(tsc).next_u64();
+}
+
+// Small generator to stretch one seed into several numbers
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        return SplitMix64 { state: seed };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+}
@@ -3,10 +3,10 @@
 
 use volatile::Volatile;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use x86_64::instructions::port::Port;
 
 use crate::console::Color;
+use crate::sync::IrqSpinLock;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -146,11 +146,11 @@ impl Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinLock<Writer> = IrqSpinLock::new("VGA writer", Writer {
         column_position: 0,
         row_position: 0,
         color_code: ColorCode::new(Color::LightGray, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer: unsafe { &mut *(crate::memory::phys_to_virt(0xb8000) as *mut Buffer) },
     });
 }
 
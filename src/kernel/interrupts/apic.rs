@@ -0,0 +1,128 @@
+// Local APIC of the boot CPU. x2APIC (registers in MSRs) is used when the CPU supports it,
+// otherwise xAPIC with the registers mapped from the physical base in IA32_APIC_BASE.
+use spin::Once;
+use x86::cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+use crate::memory::{self, EntryFlags, VirtualAddress};
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// register offsets in the xAPIC page, x2APIC MSRs are at X2APIC_MSR_BASE + offset / 16
+const ID: u32 = 0x20;
+const TASK_PRIORITY: u32 = 0x80;
+const END_OF_INTERRUPT: u32 = 0xb0;
+const SPURIOUS_INTERRUPT: u32 = 0xf0;
+const ERROR_STATUS: u32 = 0x280;
+const LVT_TIMER: u32 = 0x320;
+const LVT_LINT0: u32 = 0x350;
+const LVT_LINT1: u32 = 0x360;
+const LVT_ERROR: u32 = 0x370;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    XApic(VirtualAddress),
+    X2Apic,
+}
+
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        return match self.mode {
+            Mode::XApic(base) => core::ptr::read_volatile((base + register as usize) as *const u32),
+            Mode::X2Apic => Msr::new(X2APIC_MSR_BASE + register / 16).read() as u32,
+        };
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        match self.mode {
+            Mode::XApic(base) => core::ptr::write_volatile((base + register as usize) as *mut u32, value),
+            Mode::X2Apic => Msr::new(X2APIC_MSR_BASE + register / 16).write(value as u64),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        let id = unsafe { self.read(ID) };
+        return match self.mode {
+            Mode::XApic(_) => id >> 24,
+            Mode::X2Apic => id,
+        };
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        return matches!(self.mode, Mode::X2Apic);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(END_OF_INTERRUPT, 0) };
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+// Enables the local APIC, returns false if the CPU has none
+pub fn init() -> bool {
+    assert_has_not_been_called!("apic::init can be called only once");
+
+    let features = match CpuId::new().get_feature_info() {
+        Some(features) if features.has_apic() => features,
+        _ => return false,
+    };
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let mut base = unsafe { base_msr.read() } | APIC_BASE_ENABLE;
+
+    let mode = if features.has_x2apic() {
+        base |= APIC_BASE_X2APIC_ENABLE;
+        unsafe { base_msr.write(base) };
+        Mode::X2Apic
+    } else {
+        unsafe { base_msr.write(base) };
+        let physical_base = (base & APIC_BASE_ADDRESS_MASK) as usize;
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+        let virtual_base = memory::controller().map_physical(physical_base, 0x1000, flags)
+            .expect("no virtual space left for the local APIC");
+        Mode::XApic(virtual_base)
+    };
+
+    let apic = LOCAL_APIC.call_once(|| LocalApic { mode: mode });
+
+    unsafe {
+        apic.write(TASK_PRIORITY, 0);
+
+        // external interrupts come through the I/O APIC, LINT1 is wired to NMI on PCs
+        apic.write(LVT_TIMER, LVT_MASKED);
+        apic.write(LVT_LINT0, LVT_MASKED);
+        apic.write(LVT_LINT1, DELIVERY_NMI);
+        apic.write(LVT_ERROR, LVT_MASKED);
+
+        // the error status register is updated by a write, the second one clears it
+        apic.write(ERROR_STATUS, 0);
+        apic.write(ERROR_STATUS, 0);
+
+        apic.write(SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    // acknowledge anything that was pending before
+    apic.end_of_interrupt();
+
+    return true;
+}
+
+pub fn get() -> Option<&'static LocalApic> {
+    return LOCAL_APIC.get();
+}
@@ -0,0 +1,130 @@
+// Deferred interrupt work. Interrupt handlers only acknowledge the hardware and schedule the rest:
+// - tasklets run when the outermost IRQ handler exits, with interrupts enabled, one at a time.
+//   They must not sleep or take locks which are held with interrupts enabled.
+// - work items run in thread context, from `run_work` (the kernel thread calls it when it idles),
+//   so they can take any lock.
+// Both are scheduled at most once until they start running, scheduling them again while they
+// run makes them run once more.
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::sync::IrqSpinLock;
+
+const QUEUE_SIZE: usize = 64;
+
+pub struct Tasklet {
+    func: fn(),
+    pending: AtomicBool,
+}
+
+pub struct Work {
+    func: fn(),
+    pending: AtomicBool,
+}
+
+struct Queue<T: 'static> {
+    items: [Option<&'static T>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl<T> Queue<T> {
+    const fn new() -> Queue<T> {
+        return Queue {
+            items: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        };
+    }
+
+    fn push(&mut self, item: &'static T) {
+        assert!(self.len < QUEUE_SIZE, "deferred work queue is full");
+        self.items[(self.head + self.len) % QUEUE_SIZE] = Some(item);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<&'static T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        return item;
+    }
+}
+
+static TASKLETS: IrqSpinLock<Queue<Tasklet>> = IrqSpinLock::new("tasklets", Queue::new());
+static WORK: IrqSpinLock<Queue<Work>> = IrqSpinLock::new("work", Queue::new());
+
+static RUNNING_TASKLETS: AtomicBool = AtomicBool::new(false);
+
+impl Tasklet {
+    pub const fn new(func: fn()) -> Tasklet {
+        return Tasklet {
+            func: func,
+            pending: AtomicBool::new(false),
+        };
+    }
+
+    pub fn schedule(&'static self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            TASKLETS.lock().push(self);
+        }
+    }
+}
+
+impl Work {
+    pub const fn new(func: fn()) -> Work {
+        return Work {
+            func: func,
+            pending: AtomicBool::new(false),
+        };
+    }
+
+    pub fn queue(&'static self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            WORK.lock().push(self);
+        }
+    }
+}
+
+// Called by the IRQ dispatcher after the EOI, with interrupts disabled. An IRQ that arrives while
+// tasklets run leaves its tasklets to the outer one, which keeps running until the queue is empty.
+pub(super) fn run_tasklets() {
+    if RUNNING_TASKLETS.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    loop {
+        // the queue must not stay locked while the tasklet runs
+        let tasklet = match TASKLETS.lock().pop() {
+            Some(tasklet) => tasklet,
+            None => break,
+        };
+        tasklet.pending.store(false, Ordering::Release);
+
+        interrupts::enable();
+        (tasklet.func)();
+        interrupts::disable();
+    }
+
+    RUNNING_TASKLETS.store(false, Ordering::Release);
+}
+
+// Whether a tasklet is running, interrupt handlers that interrupted it included
+pub fn in_tasklet() -> bool {
+    return RUNNING_TASKLETS.load(Ordering::Relaxed);
+}
+
+// Runs all queued work, must not be called from interrupt context
+pub fn run_work() {
+    loop {
+        let work = match WORK.lock().pop() {
+            Some(work) => work,
+            None => break,
+        };
+        work.pending.store(false, Ordering::Release);
+        (work.func)();
+    }
+}
@@ -0,0 +1,318 @@
+// Handlers for the architectural exceptions (vectors 0-31). Every vector gets a small assembly stub,
+// which saves the general purpose registers and calls `exception_handler` with the whole frame,
+// so faults end in a panic with a full register dump instead of a triple fault.
+use core::arch::{asm, global_asm};
+use core::fmt;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+
+use crate::memory::{self, PAGE_SIZE};
+use super::{DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX};
+
+const EXCEPTION_COUNT: usize = 32;
+
+const DEBUG: u64 = 1;
+const DOUBLE_FAULT: u64 = 8;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+
+// (mnemonic, name), indexed by vector
+const EXCEPTIONS: [(&str, &str); EXCEPTION_COUNT] = [
+    ("#DE", "divide error"),
+    ("#DB", "debug"),
+    ("NMI", "non-maskable interrupt"),
+    ("#BP", "breakpoint"),
+    ("#OF", "overflow"),
+    ("#BR", "bound range exceeded"),
+    ("#UD", "invalid opcode"),
+    ("#NM", "device not available"),
+    ("#DF", "double fault"),
+    ("", "coprocessor segment overrun"),
+    ("#TS", "invalid TSS"),
+    ("#NP", "segment not present"),
+    ("#SS", "stack-segment fault"),
+    ("#GP", "general protection fault"),
+    ("#PF", "page fault"),
+    ("", "reserved exception"),
+    ("#MF", "x87 floating-point exception"),
+    ("#AC", "alignment check"),
+    ("#MC", "machine check"),
+    ("#XM", "SIMD floating-point exception"),
+    ("#VE", "virtualization exception"),
+    ("#CP", "control protection exception"),
+    ("", "reserved exception"),
+    ("", "reserved exception"),
+    ("", "reserved exception"),
+    ("", "reserved exception"),
+    ("", "reserved exception"),
+    ("", "reserved exception"),
+    ("#HV", "hypervisor injection exception"),
+    ("#VC", "VMM communication exception"),
+    ("#SX", "security exception"),
+    ("", "reserved exception"),
+];
+
+// Saved by `exception_common`, the last pushed register comes first
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    // 0 for the exceptions that do not push one
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+global_asm!(r#"
+.section .text
+
+.macro exception_stub vector, has_error_code
+exception_stub_\vector:
+    .if \has_error_code == 0
+    push 0
+    .endif
+    push \vector
+    jmp exception_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 15, 0
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 22, 0
+exception_stub 23, 0
+exception_stub 24, 0
+exception_stub 25, 0
+exception_stub 26, 0
+exception_stub 27, 0
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+exception_stub 31, 0
+
+// The CPU aligns the stack to 16 bytes before pushing its frame, with the vector, the error code
+// and the 15 registers the stack stays aligned for the call
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    cld
+    call exception_handler
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    // vector and error code
+    add rsp, 16
+    iretq
+
+.section .rodata
+.balign 8
+.global exception_stubs
+exception_stubs:
+    .quad exception_stub_0, exception_stub_1, exception_stub_2, exception_stub_3
+    .quad exception_stub_4, exception_stub_5, exception_stub_6, exception_stub_7
+    .quad exception_stub_8, exception_stub_9, exception_stub_10, exception_stub_11
+    .quad exception_stub_12, exception_stub_13, exception_stub_14, exception_stub_15
+    .quad exception_stub_16, exception_stub_17, exception_stub_18, exception_stub_19
+    .quad exception_stub_20, exception_stub_21, exception_stub_22, exception_stub_23
+    .quad exception_stub_24, exception_stub_25, exception_stub_26, exception_stub_27
+    .quad exception_stub_28, exception_stub_29, exception_stub_30, exception_stub_31
+"#);
+
+extern "C" {
+    static exception_stubs: [u64; EXCEPTION_COUNT];
+}
+
+fn stub(vector: u64) -> VirtAddr {
+    return VirtAddr::new(unsafe { exception_stubs[vector as usize] });
+}
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0));
+        idt.debug.set_handler_addr(stub(1));
+        idt.non_maskable_interrupt.set_handler_addr(stub(2)).set_stack_index(NMI_IST_INDEX as u16);
+        idt.breakpoint.set_handler_addr(stub(3));
+        idt.overflow.set_handler_addr(stub(4));
+        idt.bound_range_exceeded.set_handler_addr(stub(5));
+        idt.invalid_opcode.set_handler_addr(stub(6));
+        idt.device_not_available.set_handler_addr(stub(7));
+        idt.double_fault.set_handler_addr(stub(DOUBLE_FAULT)).set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+        idt.invalid_tss.set_handler_addr(stub(INVALID_TSS));
+        idt.segment_not_present.set_handler_addr(stub(SEGMENT_NOT_PRESENT));
+        idt.stack_segment_fault.set_handler_addr(stub(STACK_SEGMENT_FAULT));
+        idt.general_protection_fault.set_handler_addr(stub(GENERAL_PROTECTION_FAULT));
+        idt.page_fault.set_handler_addr(stub(PAGE_FAULT));
+        idt.x87_floating_point.set_handler_addr(stub(16));
+        idt.alignment_check.set_handler_addr(stub(17));
+        idt.machine_check.set_handler_addr(stub(18)).set_stack_index(MACHINE_CHECK_IST_INDEX as u16);
+        idt.simd_floating_point.set_handler_addr(stub(19));
+        idt.virtualization.set_handler_addr(stub(20));
+        idt.cp_protection_exception.set_handler_addr(stub(21));
+        idt.hv_injection_exception.set_handler_addr(stub(28));
+        idt.vmm_communication_exception.set_handler_addr(stub(29));
+        idt.security_exception.set_handler_addr(stub(30));
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    if frame.vector == PAGE_FAULT {
+        let address = Cr2::read().as_u64() as usize;
+        if memory::fault::handle_page_fault(address, PageFaultErrorCode::from_bits_truncate(frame.error_code)) {
+            return;
+        }
+    }
+
+    if let Some(context) = stack_overflow(frame) {
+        panic!("stack overflow in {}\n{}", context, frame);
+    }
+
+    panic!("{}", frame);
+}
+
+fn stack_overflow(frame: &ExceptionFrame) -> Option<&'static str> {
+    let address = Cr2::read().as_u64() as usize;
+    return match frame.vector {
+        // a present page is never a guard
+        PAGE_FAULT if !PageFaultErrorCode::from_bits_truncate(frame.error_code).contains(PageFaultErrorCode::PROTECTION_VIOLATION) =>
+            memory::fault::stack_overflow(address),
+        // the page fault could not be pushed on the overflowed stack, CR2 still holds its address
+        DOUBLE_FAULT if address.abs_diff(frame.rsp as usize) < PAGE_SIZE => memory::fault::stack_overflow(address),
+        _ => None,
+    };
+}
+
+// Error code of #TS, #NP, #SS and #GP, 0 if the exception is not related to a segment
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "no selector");
+        }
+
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {}", table, (self.0 >> 3) & 0x1fff)?;
+        if self.0 & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        return Ok(());
+    }
+}
+
+fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack)) };
+    return value;
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mnemonic, name) = EXCEPTIONS[self.vector as usize % EXCEPTION_COUNT];
+        write!(f, "{} {} (vector {}, error code {:#x})", name, mnemonic, self.vector, self.error_code)?;
+
+        match self.vector {
+            DEBUG => write!(f, ": DR6 {:#x}", read_dr6())?,
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                write!(f, ": {}", SelectorErrorCode(self.error_code))?
+            }
+            PAGE_FAULT => {
+                write!(f, ": {:?}, accessed address {:#x}", PageFaultErrorCode::from_bits_truncate(self.error_code),
+                       Cr2::read().as_u64())?
+            }
+            _ => {}
+        }
+
+        let r = &self.registers;
+        writeln!(f)?;
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", r.rax, r.rbx, r.rcx, r.rdx)?;
+        writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", r.rsi, r.rdi, r.rbp, self.rsp)?;
+        writeln!(f, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", r.r8, r.r9, r.r10, r.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", r.r12, r.r13, r.r14, r.r15)?;
+        writeln!(f, "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}", self.rip, self.rflags, self.cs, self.ss)?;
+        write!(f, "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+               Cr0::read_raw(), Cr2::read().as_u64(), Cr3::read().0.start_address().as_u64(), Cr4::read_raw())?;
+
+        return Ok(());
+    }
+}
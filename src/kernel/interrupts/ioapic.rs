@@ -0,0 +1,207 @@
+// I/O APIC, routes the external interrupt lines (global system interrupts) to local APICs.
+// ISA IRQs are connected to the same GSI, except for the overrides reported by the firmware.
+use crate::memory::{self, EntryFlags, PhysicalAddress, VirtualAddress};
+use crate::sync::IrqSpinLock;
+
+// Standard location, used when there is no ACPI MADT listing the I/O APICs
+pub const DEFAULT_ADDRESS: PhysicalAddress = 0xfec0_0000;
+
+const MAX_IO_APICS: usize = 8;
+const ISA_IRQS: usize = 16;
+
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const VECTOR_MASK: u64 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IsaOverride {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtualAddress,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        core::ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+        return core::ptr::read_volatile((self.base + REGISTER_WINDOW) as *const u32);
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        core::ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+        core::ptr::write_volatile((self.base + REGISTER_WINDOW) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        return gsi >= self.gsi_base && gsi < self.gsi_base + self.entries;
+    }
+
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let register = REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        return unsafe { self.read(register) as u64 | (self.read(register + 1) as u64) << 32 };
+    }
+
+    fn write_entry(&self, gsi: u32, entry: u64) {
+        let register = REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        unsafe {
+            // the masked low half goes first, so a half written entry never fires
+            self.write(register, MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+}
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    overrides: [IsaOverride; ISA_IRQS],
+}
+
+static IO_APICS: IrqSpinLock<IoApics> = IrqSpinLock::new("I/O APICs", IoApics {
+    apics: [None; MAX_IO_APICS],
+    overrides: default_overrides(),
+});
+
+// The PIT is connected to pin 2 on practically every PC, the firmware usually reports that
+// as an override, but without one this is still the better guess
+const fn default_overrides() -> [IsaOverride; ISA_IRQS] {
+    let mut overrides = [IsaOverride { gsi: 0, polarity: Polarity::ActiveHigh, trigger: Trigger::Edge }; ISA_IRQS];
+    let mut irq = 0;
+    while irq < ISA_IRQS {
+        overrides[irq].gsi = irq as u32;
+        irq += 1;
+    }
+    overrides[0].gsi = 2;
+    return overrides;
+}
+
+// Maps the I/O APIC and masks all of its lines, returns false if nothing answers at `address`
+pub fn add(address: PhysicalAddress, gsi_base: u32) -> bool {
+    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+    let base = memory::controller().map_physical(address, 0x20, flags).expect("no virtual space left for the I/O APIC");
+
+    let mut apic = IoApic { base: base, gsi_base: gsi_base, entries: 0 };
+    let version = unsafe { apic.read(VERSION) };
+    if version == 0xffff_ffff {
+        memory::controller().unmap_physical(base, 0x20);
+        return false;
+    }
+    apic.entries = ((version >> 16) & 0xff) + 1;
+
+    for gsi in gsi_base..gsi_base + apic.entries {
+        apic.write_entry(gsi, MASKED);
+    }
+
+    let mut io_apics = IO_APICS.lock();
+    match io_apics.apics.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(apic),
+        None => panic!("too many I/O APICs"),
+    }
+    return true;
+}
+
+pub fn set_isa_override(irq: u8, isa_override: IsaOverride) {
+    IO_APICS.lock().overrides[irq as usize] = isa_override;
+}
+
+pub fn is_present() -> bool {
+    return IO_APICS.lock().apics.iter().any(|apic| apic.is_some());
+}
+
+// ISA IRQs go through the overrides, lines above them are PCI interrupts, which are active low
+// and level triggered. None if the GSI of the line belongs to another one, eg. GSI 2 is the PIT
+// (IRQ 0) and not IRQ 2.
+fn line_config(io_apics: &IoApics, line: u8) -> Option<IsaOverride> {
+    let config = if (line as usize) < ISA_IRQS {
+        io_apics.overrides[line as usize]
+    } else {
+        IsaOverride { gsi: line as u32, polarity: Polarity::ActiveLow, trigger: Trigger::Level }
+    };
+
+    // an override takes the GSI away from the line with the same number
+    let claimed = io_apics.overrides.iter().enumerate().any(|(irq, isa_override)| {
+        irq != line as usize && isa_override.gsi == config.gsi && (isa_override.gsi != irq as u32 || line as usize >= ISA_IRQS)
+    });
+    if claimed {
+        return None;
+    }
+    return Some(config);
+}
+
+// Delivers the IRQ line as `vector` to the local APIC `destination`, the line stays masked.
+// Returns false if no I/O APIC has a pin for it, or if its pin belongs to another line.
+pub fn route_irq(line: u8, vector: u8, destination: u32) -> bool {
+    // the destination field has 8 bits, bigger x2APIC ids would need interrupt remapping
+    assert!(destination <= 0xff, "local APIC id {} cannot be reached by the I/O APIC", destination);
+
+    let io_apics = IO_APICS.lock();
+    let config = match line_config(&io_apics, line) {
+        Some(config) => config,
+        None => return false,
+    };
+    let apic = match find(&io_apics, config.gsi) {
+        Some(apic) => apic,
+        None => return false,
+    };
+
+    // entries start out with vector 0 (see `add`), two lines must never share one
+    let routed = apic.read_entry(config.gsi) & VECTOR_MASK;
+    assert!(routed == 0 || routed == vector as u64, "GSI {} is routed to vector {} already, cannot route IRQ {} to it",
+            config.gsi, routed, line);
+
+    let mut entry = vector as u64 | MASKED | (destination as u64) << 56;
+    if config.polarity == Polarity::ActiveLow {
+        entry |= ACTIVE_LOW;
+    }
+    if config.trigger == Trigger::Level {
+        entry |= LEVEL_TRIGGERED;
+    }
+
+    apic.write_entry(config.gsi, entry);
+    return true;
+}
+
+pub fn set_irq_masked(line: u8, masked: bool) -> bool {
+    let io_apics = IO_APICS.lock();
+    let gsi = match line_config(&io_apics, line) {
+        Some(config) => config.gsi,
+        None => return false,
+    };
+    let apic = match find(&io_apics, gsi) {
+        Some(apic) => apic,
+        None => return false,
+    };
+
+    let entry = apic.read_entry(gsi);
+    apic.write_entry(gsi, if masked { entry | MASKED } else { entry & !MASKED });
+    return true;
+}
+
+fn find(io_apics: &IoApics, gsi: u32) -> Option<IoApic> {
+    return io_apics.apics.iter().flatten().find(|apic| apic.handles(gsi)).copied();
+}
@@ -0,0 +1,174 @@
+// Hardware IRQ lines, line N arrives at vector PIC_1_OFFSET + N with both interrupt controllers.
+// Drivers register handlers at runtime, several handlers can share a line (PCI interrupts do),
+// and the EOI is sent here after all of them ran.
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use super::PIC_1_OFFSET;
+use crate::sync::IrqSpinLock;
+
+// 16 ISA lines and the 8 PCI lines of the first I/O APIC, the 8259 has only the ISA ones
+pub const IRQ_LINES: usize = 24;
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+// Called with the line number, returns true if its device raised the interrupt
+pub type IrqHandler = fn(line: u8) -> bool;
+
+#[derive(Clone, Copy)]
+struct Action {
+    handler: IrqHandler,
+    name: &'static str,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrqStats {
+    pub line: u8,
+    pub count: u64,
+    // interrupts no handler claimed
+    pub unhandled: u64,
+    pub handlers: [Option<&'static str>; MAX_SHARED_HANDLERS],
+}
+
+static ACTIONS: IrqSpinLock<[[Option<Action>; MAX_SHARED_HANDLERS]; IRQ_LINES]> = IrqSpinLock::new("irq actions", [[None; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+static COUNTS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+
+// Handlers running (nested ones included), tasklets run outside of it
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+macro_rules! irq_stubs {
+    ($($line:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: InterruptStackFrame) {
+                dispatch($line);
+            }
+        )*
+
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [$($stub),*];
+    };
+}
+
+irq_stubs!(
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3, 4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11, 12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15,
+    16 => irq_16, 17 => irq_17, 18 => irq_18, 19 => irq_19, 20 => irq_20, 21 => irq_21, 22 => irq_22, 23 => irq_23,
+);
+
+pub fn vector(line: u8) -> u8 {
+    return PIC_1_OFFSET + line;
+}
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for (line, stub) in STUBS.iter().enumerate() {
+        idt[usize::from(vector(line as u8))].set_handler_fn(*stub);
+    }
+}
+
+fn dispatch(line: u8) {
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    handle(line);
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+    // the rest of the work, with interrupts enabled again
+    super::deferred::run_tasklets();
+}
+
+fn handle(line: u8) {
+    if super::is_spurious(line) {
+        return;
+    }
+
+    COUNTS[line as usize].fetch_add(1, Ordering::Relaxed);
+
+    // copied, so handlers can (un)register handlers themselves
+    let actions = ACTIONS.lock()[line as usize];
+
+    let mut handled = false;
+    for action in actions.iter().flatten() {
+        handled |= (action.handler)(line);
+    }
+    if !handled {
+        UNHANDLED[line as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    super::end_of_interrupt(line);
+}
+
+// Whether an interrupt handler is running, tasklets excluded
+pub fn in_irq() -> bool {
+    return DEPTH.load(Ordering::Relaxed) > 0;
+}
+
+// Adds a handler to the line and unmasks it. Returns false if the line does not exist with the
+// active interrupt controller, or if it already has MAX_SHARED_HANDLERS handlers.
+pub fn register_irq(line: u8, handler: IrqHandler, name: &'static str) -> bool {
+    if line as usize >= IRQ_LINES {
+        return false;
+    }
+
+    let mut actions = ACTIONS.lock();
+    let line_actions = &mut actions[line as usize];
+    let first = line_actions.iter().all(|action| action.is_none());
+
+    let slot = match line_actions.iter_mut().find(|action| action.is_none()) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    if first && !super::set_irq_masked(line, false) {
+        return false;
+    }
+
+    *slot = Some(Action { handler: handler, name: name });
+    return true;
+}
+
+// Removes the handler registered under `name`, the line is masked when its last handler goes
+pub fn unregister_irq(line: u8, name: &str) -> bool {
+    if line as usize >= IRQ_LINES {
+        return false;
+    }
+
+    let mut actions = ACTIONS.lock();
+    let line_actions = &mut actions[line as usize];
+
+    let slot = match line_actions.iter_mut().find(|action| action.map_or(false, |action| action.name == name)) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    *slot = None;
+
+    if line_actions.iter().all(|action| action.is_none()) {
+        super::set_irq_masked(line, true);
+    }
+    return true;
+}
+
+pub fn stats(line: u8) -> Option<IrqStats> {
+    if line as usize >= IRQ_LINES {
+        return None;
+    }
+
+    let actions = ACTIONS.lock()[line as usize];
+    let mut handlers = [None; MAX_SHARED_HANDLERS];
+    for (name, action) in handlers.iter_mut().zip(actions.iter()) {
+        *name = action.map(|action| action.name);
+    }
+
+    return Some(IrqStats {
+        line: line,
+        count: COUNTS[line as usize].load(Ordering::Relaxed),
+        unhandled: UNHANDLED[line as usize].load(Ordering::Relaxed),
+        handlers: handlers,
+    });
+}
+
+// Lines which have a handler or have fired at least once
+pub fn for_each_irq<F>(mut f: F) where F: FnMut(IrqStats) {
+    for line in 0..IRQ_LINES as u8 {
+        let stats = stats(line).unwrap();
+        if stats.count > 0 || stats.handlers.iter().any(|handler| handler.is_some()) {
+            f(stats);
+        }
+    }
+}
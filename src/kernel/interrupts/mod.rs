@@ -1,25 +1,31 @@
 use x86_64::VirtAddr;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::structures::tss::TaskStateSegment;
-use spin::{Once, Mutex};
+use spin::Once;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 
 mod gdt;
-
-use crate::memory::MemoryController;
+mod exceptions;
+pub mod apic;
+pub mod ioapic;
+pub mod irq;
+pub mod deferred;
+
+use crate::acpi;
+use crate::cmdline;
+use crate::memory;
+use crate::sync::IrqSpinLock;
 use crate::timer;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        unsafe {
-            idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(INTERRUPT_IST_INDEX as u16);
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(INTERRUPT_IST_INDEX as u16);
-            idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler).set_stack_index(INTERRUPT_IST_INDEX as u16);
-            idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler).set_stack_index(INTERRUPT_IST_INDEX as u16);
-        }
+        exceptions::install(&mut idt);
+
+        irq::install(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_interrupt_handler);
 
         idt
     };
@@ -27,43 +33,42 @@ lazy_static! {
 
 static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::Gdt> = Once::new();
+static CONTROLLER: Once<InterruptController> = Once::new();
 
-const INTERRUPT_IST_INDEX: usize = 0;
+// Exceptions which can hit at any time, even with a broken kernel stack, run on their own stacks.
+// Everything else, page faults and IRQs included, runs on the interrupted kernel stack.
+const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const NMI_IST_INDEX: usize = 1;
+const MACHINE_CHECK_IST_INDEX: usize = 2;
+const IST_STACK_PAGES: usize = 4;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-// TODO: Migrate to APIC
-pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// Only used when there is no APIC (or with `noapic`), otherwise it is remapped and fully masked
+pub static PICS: IrqSpinLock<ChainedPics> = IrqSpinLock::new("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-#[derive(Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    Pic,
+    Apic,
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        return self as u8;
-    }
-
-    fn as_usize(self) -> usize {
-        return usize::from(self.as_u8());
-    }
-}
-
-
-pub fn init(memory_controller: &mut MemoryController) {
+pub fn init() {
     use x86_64::structures::gdt::SegmentSelector;
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
-    let interrupt_stack = memory_controller.alloc_stack(4).expect("could not allocate double fault stack");
-
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[INTERRUPT_IST_INDEX] = VirtAddr::new(interrupt_stack.top() as u64);
+        for &(index, name) in &[(DOUBLE_FAULT_IST_INDEX, "double fault handler"),
+                                (NMI_IST_INDEX, "NMI handler"),
+                                (MACHINE_CHECK_IST_INDEX, "machine check handler")] {
+            let stack = memory::controller().alloc_stack(IST_STACK_PAGES).expect("could not allocate an interrupt stack");
+            // the stacks live as long as the kernel, so their guards get names
+            memory::fault::register_guard(name, stack.bottom() - memory::PAGE_SIZE, stack.bottom());
+            tss.interrupt_stack_table[index] = VirtAddr::new(stack.top() as u64);
+        }
         tss
     });
 
@@ -86,56 +91,142 @@ pub fn init(memory_controller: &mut MemoryController) {
 
     IDT.load();
 
+    // remapped in any case, so stray IRQs from the PIC never look like exceptions
     unsafe {
         PICS.lock().initialize();
     }
 
+    let controller = if !cmdline::flag("noapic") && init_apic() {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    };
+    CONTROLLER.call_once(|| controller);
+
+    // every line stays masked until a handler is registered for it
+    for line in 0..irq::IRQ_LINES as u8 {
+        set_irq_masked(line, true);
+    }
+    irq::register_irq(0, timer_interrupt, "timer");
+
     timer::configure_pit();
 
     x86_64::instructions::interrupts::enable();
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-    panic!("page fault ({:?}):\naccesed address: {:?}:\n{:#?}", error_code, Cr2::read(), stack_frame);
+// Masks the PIC and routes the legacy IRQs through the I/O APIC, returns false if either APIC is missing.
+// The I/O APICs and the IRQ wiring come from the ACPI MADT, without it the standard PC layout is assumed.
+fn init_apic() -> bool {
+    use x86_64::instructions::port::Port;
+
+    let has_pics = match acpi::madt() {
+        Some(madt) => {
+            for io_apic in &madt.io_apics {
+                ioapic::add(io_apic.address, io_apic.gsi_base);
+            }
+            // with a MADT, IRQs without an override are identity mapped, the PIT included
+            for irq in 0..16 {
+                let isa_override = match madt.isa_override(irq) {
+                    Some(isa_override) => ioapic::IsaOverride {
+                        gsi: isa_override.gsi,
+                        polarity: isa_override.polarity,
+                        trigger: isa_override.trigger,
+                    },
+                    None => ioapic::IsaOverride { gsi: irq as u32, polarity: ioapic::Polarity::ActiveHigh, trigger: ioapic::Trigger::Edge },
+                };
+                ioapic::set_isa_override(irq, isa_override);
+            }
+            madt.has_pics
+        }
+        None => {
+            ioapic::add(ioapic::DEFAULT_ADDRESS, 0);
+            true
+        }
+    };
+
+    if !ioapic::is_present() || !apic::init() {
+        return false;
+    }
+
+    if has_pics {
+        unsafe {
+            Port::<u8>::new(0x21).write(0xff);
+            Port::<u8>::new(0xa1).write(0xff);
+        }
+    }
+
+    // lines whose pin is taken by an ISA override (like IRQ 2 by the PIT) are left unrouted
+    let destination = apic::get().unwrap().id();
+    for line in 0..irq::IRQ_LINES as u8 {
+        ioapic::route_irq(line, irq::vector(line), destination);
+    }
+
+    return true;
+}
+
+pub fn controller() -> InterruptController {
+    return *CONTROLLER.get().unwrap_or(&InterruptController::Pic);
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-    panic!("double fault (code {}):\n{:#?}", error_code, stack_frame);
+fn end_of_interrupt(line: u8) {
+    match controller() {
+        InterruptController::Apic => apic::get().unwrap().end_of_interrupt(),
+        InterruptController::Pic => unsafe { PICS.lock().notify_end_of_interrupt(irq::vector(line)) },
+    }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame)
-{
-    timer::pit_interrupt();
+// Returns false if the line does not exist with the active controller
+fn set_irq_masked(line: u8, masked: bool) -> bool {
+    use x86_64::instructions::port::Port;
 
+    if controller() == InterruptController::Apic {
+        return ioapic::set_irq_masked(line, masked);
+    }
+    if line >= 16 {
+        return false;
+    }
+
+    // line 2 is the cascade from the slave, it is never masked
+    let (port, bit) = if line < 8 { (0x21, line) } else { (0xa1, line - 8) };
+    let mut port = Port::<u8>::new(port);
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        let mask = port.read();
+        port.write(if masked && line != 2 { mask | 1 << bit } else { mask & !(1 << bit) });
     }
+    return true;
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
-{
-    use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+fn timer_interrupt(_line: u8) -> bool {
+    timer::pit_interrupt();
+    return true;
+}
+
+// IRQ 7 and 15 are raised by the PIC when the interrupting line goes away before it is acknowledged.
+// Such an IRQ is not in service and must not get an EOI, except for the cascade line on the master.
+fn is_spurious(line: u8) -> bool {
     use x86_64::instructions::port::Port;
-    use crate::console;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(HandleControl::Ignore)
-        );
+    if controller() != InterruptController::Pic || (line != 7 && line != 15) {
+        return false;
     }
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            console::register_input(key);
-        }
+    // OCW3, the next read returns the in-service register
+    let mut command = Port::<u8>::new(if line == 15 { 0xa0 } else { 0x20 });
+    let in_service = unsafe {
+        command.write(0x0b);
+        command.read()
+    };
+    if in_service & (1 << 7) != 0 {
+        return false;
     }
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    if line == 15 {
+        unsafe { PICS.lock().notify_end_of_interrupt(irq::vector(2)) };
     }
+    return true;
+}
+
+// Never acknowledged, the local APIC does not set an in-service bit for it
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame)
+{
 }
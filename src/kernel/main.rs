@@ -10,7 +10,6 @@ extern crate spin;
 extern crate lazy_static;
 extern crate multiboot2;
 extern crate x86_64;
-extern crate linked_list_allocator;
 extern crate alloc;
 extern crate bit_field;
 extern crate pic8259;
@@ -26,13 +25,28 @@ mod console;
 mod memory;
 mod interrupts;
 mod timer;
+mod cmdline;
+mod modules;
+mod acpi;
+mod power;
+mod sync;
 
 #[panic_handler]
 fn _panic(info: &PanicInfo) -> ! {
     x86_64::instructions::interrupts::disable();
+    // the locks may be in any state now
+    #[cfg(feature = "lock-debug")]
+    sync::lockdep::disable();
 
     println_all!("\n\x1b[1;31m---[Kernel Panic: {}, at {}", info.message().unwrap(), info.location().unwrap());
 
+    // `panic=N` reboots N seconds after a panic, 0 (the default) keeps the machine halted
+    if let Some(seconds) = cmdline::value_usize("panic").filter(|&seconds| seconds > 0) {
+        println_all!("Rebooting in {} seconds", seconds);
+        timer::busy_wait(seconds as u64 * 1000);
+        power::reboot();
+    }
+
     loop {
         x86_64::instructions::hlt();
     }
@@ -40,23 +54,53 @@ fn _panic(info: &PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn _start(multiboot_info_addr: usize) {
-    let boot_info = unsafe { multiboot2::load(multiboot_info_addr) };
-
-    let cmd_tag = boot_info.command_line_tag();
-    let cmd;
+    let boot_info = unsafe { multiboot2::load(memory::phys_to_virt(multiboot_info_addr)) };
 
-    if cmd_tag.is_some() {
-        cmd = cmd_tag.unwrap().command_line().trim();
-    } else {
-        cmd = "";
+    if let Some(cmd_tag) = boot_info.command_line_tag() {
+        cmdline::init(cmd_tag.command_line());
     }
 
-    let mut memory_controller = memory::init(boot_info);
-    interrupts::init(&mut memory_controller);
+    memory::init(&boot_info);
+    modules::init(&boot_info);
+    let has_acpi = acpi::init(&boot_info);
+    interrupts::init();
     console::init();
+    drivers::keyboard::init();
 
     println_all!("\x1b[1;32mGalaxyOS v{}", env!("CARGO_PKG_VERSION"));
-    println_all!("Command line: {}", cmd);
+    println_all!("Command line: {}", cmdline::get());
+    println_all!("KASLR: {}", if memory::controller().kaslr_enabled() { "enabled" } else { "disabled (nokaslr)" });
+    if has_acpi {
+        let acpi = acpi::get().unwrap();
+        print_all!("ACPI: revision {}, OEM {}, tables:", acpi.revision, core::str::from_utf8(&acpi.oem_id).unwrap_or("?"));
+        for table in acpi::tables() {
+            print_all!(" {}", table.signature());
+        }
+        println_all!();
+    } else {
+        println_all!("ACPI: not found");
+    }
+    match interrupts::controller() {
+        interrupts::InterruptController::Apic => {
+            let apic = interrupts::apic::get().unwrap();
+            println_all!("Interrupts: {} (local APIC id {}) + I/O APIC", if apic.is_x2apic() { "x2APIC" } else { "xAPIC" }, apic.id());
+        }
+        interrupts::InterruptController::Pic => {
+            println_all!("Interrupts: 8259 PIC");
+        }
+    }
+
+    println_all!("Physical memory map:");
+    memory::memory_map::for_each_area(|area| {
+        println_all!("  [{:#014x}-{:#014x}] {}", area.start, area.end - 1, area.typ.name());
+    });
+    memory::memory_map::for_each_reservation(|reservation| {
+        println_all!("  [{:#014x}-{:#014x}] reserved: {}", reservation.start, reservation.end - 1, reservation.name);
+    });
+    for module in modules::iter() {
+        println_all!("Module {}: {} bytes at {:#x} ({})", module.name, module.size(), module.physical_start, module.command_line);
+    }
+    println_all!("{}", memory::stats());
 
     let processor = drivers::cpuid::get_processor_info();
     println_all!("\x1b[1;36mCPU: {:#?}", processor);
@@ -1,18 +1,221 @@
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+use crate::memory::{MemoryController, PAGE_SIZE};
+use crate::memory::paging::VirtualAddress;
+use crate::memory::slab::{SlabCache, CacheStats};
+use crate::sync::{SpinLock, SpinLockGuard};
 
-#[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+// The heap start is chosen at boot, see `kaslr`
+pub const HEAP_MAX_SIZE: usize = 0o_000_100_000_000_0000; // 64 GiB of reserved virtual space
+
+// Allocations bigger than the largest size class get whole pages
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+static SIZE_CACHES: [SlabCache; 9] = [
+    SlabCache::new("kmalloc-8", 8, 8),
+    SlabCache::new("kmalloc-16", 16, 16),
+    SlabCache::new("kmalloc-32", 32, 32),
+    SlabCache::new("kmalloc-64", 64, 64),
+    SlabCache::new("kmalloc-128", 128, 128),
+    SlabCache::new("kmalloc-256", 256, 256),
+    SlabCache::new("kmalloc-512", 512, 512),
+    SlabCache::new("kmalloc-1024", 1024, 1024),
+    SlabCache::new("kmalloc-2048", 2048, 2048),
+];
+
+const MAX_CACHES: usize = 64;
+
+static CACHES: SpinLock<[Option<&'static SlabCache>; MAX_CACHES]> = SpinLock::new("slab caches", [None; MAX_CACHES]);
+
+// Freed page ranges kept for reuse, if there are more of them the virtual space is just leaked
+const FREE_RANGES: usize = 128;
+
+struct HeapPages {
+    start: VirtualAddress,
+    next: VirtualAddress,
+    mapped: usize,
+    free: [(VirtualAddress, usize); FREE_RANGES],
+}
+
+static HEAP_PAGES: SpinLock<HeapPages> = SpinLock::new("heap pages", HeapPages {
+    start: 0,
+    next: 0,
+    mapped: 0,
+    free: [(0, 0); FREE_RANGES],
+});
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    // slab pages and large allocations, all of them are backed by frames
+    pub mapped: usize,
+    pub reserved: usize,
+}
+
+impl HeapPages {
+    fn take(&mut self, size: usize, align: usize) -> Option<VirtualAddress> {
+        for range in self.free.iter_mut() {
+            let (start, length) = *range;
+            if length >= size && start % align == 0 {
+                *range = if length == size { (0, 0) } else { (start + size, length - size) };
+                return Some(start);
+            }
+        }
+
+        let start = (self.next + align - 1) & !(align - 1);
+        if start + size > self.start + HEAP_MAX_SIZE {
+            return None;
+        }
+
+        if start > self.next {
+            let gap = (self.next, start - self.next);
+            self.give_back(gap.0, gap.1);
+        }
+        self.next = start + size;
+        return Some(start);
+    }
+
+    fn give_back(&mut self, start: VirtualAddress, size: usize) {
+        if start + size == self.next {
+            self.next = start;
+            return;
+        }
+
+        for range in self.free.iter_mut() {
+            if range.1 != 0 && range.0 + range.1 == start {
+                range.1 += size;
+                return;
+            }
+            if range.1 != 0 && start + size == range.0 {
+                *range = (start, range.1 + size);
+                return;
+            }
+        }
+
+        if let Some(range) = self.free.iter_mut().find(|range| range.1 == 0) {
+            *range = (start, size);
+        }
+    }
+}
+
+pub fn init(heap_start: VirtualAddress) {
+    let mut pages = HEAP_PAGES.lock();
+    pages.start = heap_start;
+    pages.next = heap_start;
+}
+
+// HEAP_PAGES is taken before the memory controller. Code holding the controller must not allocate,
+// with a single CPU a locked controller can only be held by the allocating code itself (or by the
+// code an interrupt handler interrupted), so that is reported instead of spinning forever.
+fn heap_controller() -> SpinLockGuard<'static, MemoryController> {
+    return crate::memory::try_controller().expect("heap allocation while the memory controller is locked");
+}
+
+// Reserves a page aligned range of the heap area and backs it with fresh frames
+// (right away and not on the first access, so running out of physical memory makes `alloc` return
+// null instead of faulting somewhere in the caller)
+pub fn alloc_pages(size: usize, align: usize) -> Option<VirtualAddress> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let align = core::cmp::max(align, PAGE_SIZE);
+
+    let mut pages = HEAP_PAGES.lock();
+    assert!(pages.start != 0, "heap is not initialized");
+    let start = pages.take(size, align)?;
+
+    if !heap_controller().map_range(start, size) {
+        pages.give_back(start, size);
+        return None;
+    }
+
+    pages.mapped += size;
+    return Some(start);
+}
 
-pub fn init() {
-    unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_START + HEAP_SIZE);
+pub fn free_pages(start: VirtualAddress, size: usize) {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut pages = HEAP_PAGES.lock();
+    heap_controller().unmap_range(start, size);
+    pages.mapped -= size;
+    pages.give_back(start, size);
+}
+
+pub fn register_cache(cache: &'static SlabCache) {
+    let mut caches = CACHES.lock();
+    match caches.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => panic!("too many slab caches, cannot register {}", cache.name()),
+    }
+}
+
+pub fn for_each_cache<F>(mut f: F) where F: FnMut(CacheStats) {
+    let caches = *CACHES.lock();
+    for cache in caches.iter().flatten() {
+        f(cache.stats());
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    let pages = HEAP_PAGES.lock();
+    return HeapStats {
+        mapped: pages.mapped,
+        reserved: HEAP_MAX_SIZE,
+    };
+}
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    return SIZE_CLASSES.iter().position(|&class| class >= size);
+}
+
+unsafe fn raw_alloc(layout: Layout) -> *mut u8 {
+    let allocation = match size_class(&layout) {
+        Some(class) => SIZE_CACHES[class].alloc(),
+        None => alloc_pages(layout.size(), layout.align()).map(|address| NonNull::new_unchecked(address as *mut u8)),
+    };
+
+    return allocation.map_or(ptr::null_mut(), |allocation| allocation.as_ptr());
+}
+
+unsafe fn raw_dealloc(ptr: *mut u8, layout: Layout) {
+    match size_class(&layout) {
+        Some(class) => SIZE_CACHES[class].free(NonNull::new_unchecked(ptr)),
+        None => free_pages(ptr as VirtualAddress, layout.size()),
+    }
+}
+
+pub struct KernelAllocator;
+
+#[cfg(not(feature = "heap-debug"))]
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        return raw_alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        raw_dealloc(ptr, layout);
     }
 }
 
+// Every allocation gets red zones and is checked on free, see `heap_debug`
+#[cfg(feature = "heap-debug")]
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        return crate::memory::heap_debug::alloc(layout, raw_alloc);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        crate::memory::heap_debug::dealloc(ptr, layout, raw_dealloc);
+    }
+}
+
+#[global_allocator]
+static HEAP_ALLOCATOR: KernelAllocator = KernelAllocator;
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    match crate::memory::try_stats() {
+        Some(stats) => panic!("allocation error (out of physical memory): {:?}\n{}", layout, stats),
+        None => panic!("allocation error (out of physical memory): {:?}", layout),
+    }
 }
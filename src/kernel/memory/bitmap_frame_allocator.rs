@@ -0,0 +1,295 @@
+use crate::memory::{PAGE_SIZE, BOOT_MAPPED_LIMIT, Frame, FrameAllocator, phys_to_virt};
+use crate::memory::paging::PhysicalAddress;
+
+use multiboot2::MemoryAreaIter;
+
+const BITS_PER_WORD: usize = 64;
+const MAX_RESERVED: usize = 32;
+
+// Physical memory zones, for devices which can address only a part of physical memory.
+// Allocations without a limit are taken from the highest zone first, so low memory is left for DMA.
+// Until the kernel has mapped all of physical memory, only frames below the boot mapping are handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Dma,
+    Dma32,
+    Normal,
+}
+
+const ZONE_COUNT: usize = 3;
+
+impl Zone {
+    pub const ALL: [Zone; ZONE_COUNT] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    pub fn containing_address(address: PhysicalAddress) -> Zone {
+        return *Zone::ALL.iter().find(|zone| address < zone.end_address()).unwrap();
+    }
+
+    pub fn start_address(self) -> PhysicalAddress {
+        return match self {
+            Zone::Dma => 0,
+            Zone::Dma32 => 0x100_0000,
+            Zone::Normal => 0x1_0000_0000,
+        };
+    }
+
+    pub fn end_address(self) -> PhysicalAddress {
+        return match self {
+            Zone::Dma => 0x100_0000,
+            Zone::Dma32 => 0x1_0000_0000,
+            Zone::Normal => usize::MAX,
+        };
+    }
+
+    pub fn name(self) -> &'static str {
+        return match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        };
+    }
+}
+
+// One bit per physical frame, a set bit means that the frame is used (or does not exist).
+// Allocated frames also have a reference count, so a frame can be shared by several mappings
+// and is freed only when the last of them goes away. Reserved frames have no references.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    references: &'static mut [u16],
+    frame_count: usize,
+    free_frames: usize,
+    // per zone, there are no free frames below these
+    next_free: [usize; ZONE_COUNT],
+    // no frames are allocated at or above this one, they could not be reached through the physmap
+    mapped_end: usize,
+    metadata_start: Frame,
+    metadata_end: Frame,
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        return self.allocate_frames(1);
+    }
+
+    fn allocate_contiguous(&mut self, count: usize, alignment: usize, max_address: PhysicalAddress) -> Option<Frame> {
+        assert!(alignment.is_power_of_two(), "alignment {:#x} is not a power of two", alignment);
+        return self.allocate_run(count, core::cmp::max(alignment / PAGE_SIZE, 1), max_address / PAGE_SIZE);
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.deallocate_frames(frame, 1);
+    }
+
+    fn share_frame(&mut self, frame: &Frame) {
+        let references = &mut self.references[frame.number];
+        assert!(*references > 0, "frame {:#x} is not allocated", frame.start_address());
+        *references = references.checked_add(1).expect("frame reference count overflow");
+    }
+
+    fn frame_references(&self, frame: &Frame) -> usize {
+        return self.references[frame.number] as usize;
+    }
+}
+
+impl BitmapFrameAllocator {
+    // `reserved` are the physical ranges (with exclusive ends) which are in use already, like the kernel image
+    pub fn new(reserved: &[(PhysicalAddress, PhysicalAddress)], memory_areas: MemoryAreaIter) -> BitmapFrameAllocator {
+        let last_address = memory_areas.clone().map(|area| area.end_address() as usize).max().expect("no usable memory areas");
+        let frame_count = Frame::containing_address(last_address - 1).number + 1;
+
+        let bitmap_words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_frame_count = (bitmap_words * 8 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let references_frame_count = (frame_count * 2 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let metadata_frame_count = bitmap_frame_count + references_frame_count;
+
+        let mut reserved_frames = [(0, 0); MAX_RESERVED];
+        assert!(reserved.len() <= MAX_RESERVED, "too many reserved ranges");
+        for (frames, &(start, end)) in reserved_frames.iter_mut().zip(reserved.iter()) {
+            *frames = (Frame::containing_address(start).number, Frame::containing_address(end).number);
+        }
+        let reserved_frames = &reserved_frames[..reserved.len()];
+
+        // the reference counts are stored right after the bitmap
+        let metadata_start = Self::find_free_run(memory_areas.clone(), metadata_frame_count, reserved_frames)
+            .expect("no space for the frame bitmap");
+        let metadata_end = metadata_start + metadata_frame_count - 1;
+
+        let bitmap = unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(metadata_start * PAGE_SIZE) as *mut u64, bitmap_words)
+        };
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let references = unsafe {
+            let start = metadata_start + bitmap_frame_count;
+            core::slice::from_raw_parts_mut(phys_to_virt(start * PAGE_SIZE) as *mut u16, frame_count)
+        };
+        for references in references.iter_mut() {
+            *references = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: bitmap,
+            references: references,
+            frame_count: frame_count,
+            free_frames: 0,
+            next_free: [0; ZONE_COUNT],
+            mapped_end: BOOT_MAPPED_LIMIT / PAGE_SIZE,
+            metadata_start: Frame { number: metadata_start },
+            metadata_end: Frame { number: metadata_end },
+        };
+
+        for area in memory_areas {
+            let start = (area.start_address() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end = area.end_address() as usize / PAGE_SIZE;
+            for number in start..end {
+                allocator.mark_free(number);
+            }
+        }
+
+        for &(start, end) in reserved_frames.iter() {
+            allocator.mark_used(start, end);
+        }
+        allocator.mark_used(metadata_start, metadata_end);
+
+        return allocator;
+    }
+
+    pub fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        return self.allocate_run(count, 1, self.frame_count);
+    }
+
+    // Called once the physmap covers physical memory up to `end`
+    pub fn set_mapped_end(&mut self, end: PhysicalAddress) {
+        self.mapped_end = end / PAGE_SIZE;
+    }
+
+    // Runs never cross zone boundaries, zones are tried from the highest one below `end`
+    fn allocate_run(&mut self, count: usize, alignment: usize, end: usize) -> Option<Frame> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let end = core::cmp::min(end, self.mapped_end);
+
+        for (index, zone) in Zone::ALL.iter().enumerate().rev() {
+            let zone_start = zone.start_address() / PAGE_SIZE;
+            let zone_end = core::cmp::min(core::cmp::min(zone.end_address() / PAGE_SIZE, end), self.frame_count);
+            if zone_start >= zone_end {
+                continue;
+            }
+
+            let search_start = core::cmp::max(zone_start, self.next_free[index]);
+            if let Some(start) = self.find_run(search_start, zone_end, count, alignment) {
+                self.mark_used(start, start + count - 1);
+                for references in self.references[start..start + count].iter_mut() {
+                    *references = 1;
+                }
+                // an aligned search may have skipped free frames, which must stay in reach of the hint
+                if count == 1 && alignment == 1 {
+                    self.next_free[index] = start + 1;
+                }
+                return Some(Frame { number: start });
+            }
+        }
+
+        return None;
+    }
+
+    // First run of `count` free frames between `start` and `end`, aligned to `alignment` frames
+    fn find_run(&self, start: usize, end: usize, count: usize, alignment: usize) -> Option<usize> {
+        let align_up = |number: usize| (number + alignment - 1) & !(alignment - 1);
+
+        let mut number = align_up(start);
+        while number + count <= end {
+            // skip fully used words quickly
+            if number % BITS_PER_WORD == 0 && self.bitmap[number / BITS_PER_WORD] == !0 {
+                number = align_up(number + BITS_PER_WORD);
+                continue;
+            }
+
+            match (number..number + count).find(|&number| self.is_used(number)) {
+                Some(used) => number = align_up(used + 1),
+                None => return Some(number),
+            }
+        }
+
+        return None;
+    }
+
+    // Drops one reference of every frame, frames without references left are freed
+    pub fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        assert!(frame.number + count <= self.frame_count, "frame {:?} does not exist", frame);
+
+        for number in frame.number..frame.number + count {
+            assert!(self.is_used(number) && self.references[number] > 0, "double free of frame {:#x}", number * PAGE_SIZE);
+            self.references[number] -= 1;
+            if self.references[number] == 0 {
+                self.mark_free(number);
+            }
+        }
+
+        let zone = Zone::containing_address(frame.start_address()) as usize;
+        if frame.number < self.next_free[zone] {
+            self.next_free[zone] = frame.number;
+        }
+    }
+
+    pub fn total_frames(&self) -> usize {
+        return self.frame_count;
+    }
+
+    pub fn free_frames(&self) -> usize {
+        return self.free_frames;
+    }
+
+    // Frames used by the bitmap and the reference counts
+    pub fn metadata_frames(&self) -> (Frame, Frame) {
+        return (self.metadata_start.clone(), self.metadata_end.clone());
+    }
+
+    fn is_used(&self, number: usize) -> bool {
+        return self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0;
+    }
+
+    fn mark_free(&mut self, number: usize) {
+        if self.is_used(number) {
+            self.bitmap[number / BITS_PER_WORD] &= !(1 << (number % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+
+    fn mark_used(&mut self, start: usize, end: usize) {
+        for number in start..=end {
+            if number >= self.frame_count {
+                break;
+            }
+            if !self.is_used(number) {
+                self.bitmap[number / BITS_PER_WORD] |= 1 << (number % BITS_PER_WORD);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    // The boot page tables map only the first GiB of physical memory, so the bitmap has to live there
+    fn find_free_run(memory_areas: MemoryAreaIter, count: usize, reserved: &[(usize, usize)]) -> Option<usize> {
+        let limit = BOOT_MAPPED_LIMIT / PAGE_SIZE;
+
+        for area in memory_areas {
+            let area_end = core::cmp::min(area.end_address() as usize / PAGE_SIZE, limit);
+            let mut start = (area.start_address() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+
+            'candidate: while start + count <= area_end {
+                for &(reserved_start, reserved_end) in reserved.iter() {
+                    if start <= reserved_end && start + count > reserved_start {
+                        start = reserved_end + 1;
+                        continue 'candidate;
+                    }
+                }
+                return Some(start);
+            }
+        }
+
+        return None;
+    }
+}
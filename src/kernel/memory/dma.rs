@@ -0,0 +1,55 @@
+use crate::memory::{PAGE_SIZE, Frame, FrameAllocator, phys_to_virt};
+use crate::memory::paging::{PhysicalAddress, VirtualAddress};
+
+// Physically contiguous, zeroed buffer for devices. The CPU accesses it through the physical
+// memory map, the device gets `physical_address()`.
+pub struct DmaBuffer {
+    physical_address: PhysicalAddress,
+    size: usize,
+}
+
+impl DmaBuffer {
+    // `max_address` is the end of the memory the device can reach, eg. 16 MiB for ISA DMA
+    pub fn new(size: usize, alignment: usize, max_address: PhysicalAddress) -> Option<DmaBuffer> {
+        assert!(size > 0);
+
+        let frame_count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frame = crate::memory::controller().frame_allocator.allocate_contiguous(frame_count, alignment, max_address)?;
+
+        let buffer = DmaBuffer {
+            physical_address: frame.start_address(),
+            size: frame_count * PAGE_SIZE,
+        };
+        unsafe { core::ptr::write_bytes(buffer.virtual_address() as *mut u8, 0, buffer.size) };
+
+        return Some(buffer);
+    }
+
+    pub fn physical_address(&self) -> PhysicalAddress {
+        return self.physical_address;
+    }
+
+    pub fn virtual_address(&self) -> VirtualAddress {
+        return phys_to_virt(self.physical_address);
+    }
+
+    // Rounded up to whole pages
+    pub fn size(&self) -> usize {
+        return self.size;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        return unsafe { core::slice::from_raw_parts(self.virtual_address() as *const u8, self.size) };
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        return unsafe { core::slice::from_raw_parts_mut(self.virtual_address() as *mut u8, self.size) };
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let frame = Frame::containing_address(self.physical_address);
+        crate::memory::controller().frame_allocator.deallocate_frames(frame, self.size / PAGE_SIZE);
+    }
+}
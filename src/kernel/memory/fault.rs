@@ -0,0 +1,211 @@
+// Demand paging: kernel virtual ranges are registered up front and populated on the first access,
+// see `RegionKind`.
+// Write faults on copy-on-write pages are resolved here too, and stack overflows are recognized here,
+// so the exception handlers can tell them from other faults. Kernel stacks are mapped up front, the
+// page fault handler runs on them.
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use crate::memory::{PAGE_SIZE, stack_allocator};
+use crate::memory::paging::{Page, EntryFlags, VirtualAddress, AddressSpace};
+use crate::sync::SpinLock;
+
+const MAX_REGIONS: usize = 64;
+// the boot stack and the interrupt stacks
+const MAX_GUARDS: usize = 8;
+
+// Backing of file backed regions and of file mappings in user address spaces, see `vma`
+pub trait PageSource: Sync {
+    // Fills the buffer with the page found at `offset` of the backing object
+    fn read_page(&self, offset: usize, buffer: &mut [u8]);
+}
+
+#[derive(Clone, Copy)]
+pub enum RegionKind {
+    // zeroed frames are mapped on the first access
+    ZeroFill,
+    // grows down from the region end by at most `limit` pages, the pages below the limit are never
+    // mapped and work as the guard. Only for stacks the page fault handler does not run on, a fault
+    // on the stack it runs on cannot be delivered.
+    Stack { limit: usize },
+    // pages are read from the source, `offset` is the source offset of the region start
+    FileBacked { source: &'static dyn PageSource, offset: usize },
+}
+
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub flags: EntryFlags,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    fn contains(&self, address: VirtualAddress) -> bool {
+        return address >= self.start && address < self.end;
+    }
+
+    // Lowest address a stack region may grow to, everything below is its guard
+    fn stack_limit(&self) -> Option<VirtualAddress> {
+        return match self.kind {
+            RegionKind::Stack { limit } => Some(self.end - limit * PAGE_SIZE),
+            _ => None,
+        };
+    }
+}
+
+// Unmapped range below a stack which lives as long as the kernel, `name` is the context that uses
+// the stack. The guards of other stacks are found by the layout of the stack area.
+#[derive(Clone, Copy)]
+struct Guard {
+    name: &'static str,
+    start: VirtualAddress,
+    end: VirtualAddress,
+}
+
+static REGIONS: SpinLock<[Option<Region>; MAX_REGIONS]> = SpinLock::new("demand paged regions", [None; MAX_REGIONS]);
+static GUARDS: SpinLock<[Option<Guard>; MAX_GUARDS]> = SpinLock::new("stack guards", [None; MAX_GUARDS]);
+
+pub fn register_region(region: Region) {
+    assert!(region.start % PAGE_SIZE == 0 && region.end % PAGE_SIZE == 0, "region {} is not page aligned", region.name);
+    assert!(region.start < region.end, "region {} is empty", region.name);
+    if let RegionKind::Stack { limit } = region.kind {
+        assert!(limit > 0 && limit < (region.end - region.start) / PAGE_SIZE, "stack region {} has no room for its guard", region.name);
+    }
+
+    let mut regions = REGIONS.lock();
+    for other in regions.iter().flatten() {
+        assert!(region.end <= other.start || region.start >= other.end,
+                "region {} overlaps with region {}", region.name, other.name);
+    }
+
+    match regions.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(region),
+        None => panic!("too many demand paged regions, cannot register {}", region.name),
+    }
+}
+
+// Removes the region and frees every page that has been populated in it
+pub fn unregister_region(start: VirtualAddress) {
+    let region = {
+        let mut regions = REGIONS.lock();
+        let slot = regions.iter_mut().find(|slot| slot.map_or(false, |region| region.start == start))
+            .expect("no region starts at this address");
+        slot.take().unwrap()
+    };
+
+    let mut controller = crate::memory::controller();
+    for page in Page::range_inclusive(Page::containing_address(region.start), Page::containing_address(region.end - 1)) {
+        if controller.is_mapped(page) {
+            controller.unmap_page(page);
+        }
+    }
+}
+
+pub fn register_guard(name: &'static str, start: VirtualAddress, end: VirtualAddress) {
+    assert!(start < end, "guard of {} is empty", name);
+
+    let mut guards = GUARDS.lock();
+    match guards.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(Guard { name: name, start: start, end: end }),
+        None => panic!("too many stack guards, cannot register {}", name),
+    }
+}
+
+// Name of the stack whose guard contains `address`, which has to be unmapped. The lists of named
+// guards and of stack regions are only tried, while they are locked only the stack area is checked.
+pub fn stack_overflow(address: VirtualAddress) -> Option<&'static str> {
+    if let Some(guards) = GUARDS.try_lock() {
+        let guard = guards.iter().flatten().find(|guard| address >= guard.start && address < guard.end);
+        if let Some(guard) = guard {
+            return Some(guard.name);
+        }
+    }
+
+    if let Some(regions) = REGIONS.try_lock() {
+        let region = regions.iter().flatten()
+            .find(|region| region.stack_limit().map_or(false, |limit| address >= region.start && address < limit));
+        if let Some(region) = region {
+            return Some(region.name);
+        }
+    }
+
+    if stack_allocator::is_guard(address) {
+        return Some("a kernel stack");
+    }
+    return None;
+}
+
+// A fault while the list is locked is left unresolved, like one inside of the memory controller
+fn find_region(address: VirtualAddress) -> Option<Region> {
+    let regions = REGIONS.try_lock()?;
+    return regions.iter().flatten().find(|region| region.contains(address)).copied();
+}
+
+// Called by the page fault handler, returns false if the fault is not a demand paging or copy-on-write one.
+// Faults in the user half go to the active address space.
+pub fn handle_page_fault(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+    if address < 0x0000_8000_0000_0000 {
+        return AddressSpace::handle_current_page_fault(address, error_code);
+    }
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return false;
+        }
+        return match crate::memory::try_controller() {
+            Some(mut controller) => controller.resolve_copy_on_write(Page::containing_address(address)),
+            None => false,
+        };
+    }
+
+    if address < 0xffff_8000_0000_0000 {
+        return false;
+    }
+
+    let region = match find_region(address) {
+        Some(region) => region,
+        None => return false,
+    };
+
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !region.flags.contains(EntryFlags::WRITABLE) {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && region.flags.contains(EntryFlags::NO_EXECUTE) {
+        return false;
+    }
+
+    // a fault inside of the memory controller itself cannot be resolved
+    let mut controller = match crate::memory::try_controller() {
+        Some(controller) => controller,
+        None => return false,
+    };
+
+    let page = Page::containing_address(address);
+
+    match region.kind {
+        RegionKind::ZeroFill => {
+            return controller.map_filled(page, region.flags, |buffer| buffer.fill(0));
+        }
+        RegionKind::Stack { .. } => {
+            // below the limit is the guard, `stack_overflow` reports that
+            if address < region.stack_limit().unwrap() {
+                return false;
+            }
+
+            // keep the stack contiguous, populate everything up to the part which is already mapped
+            let mut next = page;
+            while next.start_address() < region.end && !controller.is_mapped(next) {
+                if !controller.map_filled(next, region.flags, |buffer| buffer.fill(0)) {
+                    return false;
+                }
+                next = next + 1;
+            }
+            return true;
+        }
+        RegionKind::FileBacked { source, offset } => {
+            let source_offset = offset + (page.start_address() - region.start);
+            return controller.map_filled(page, region.flags, |buffer| source.read_page(source_offset, buffer));
+        }
+    }
+}
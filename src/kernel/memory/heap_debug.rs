@@ -0,0 +1,251 @@
+// Heap debugging (the `heap-debug` feature). Allocations are laid out as
+//   [padding][header][front red zone][object][back red zone]
+// Red zones are checked on free, freed objects are poisoned, and live allocations are kept in a
+// list together with their call sites, so leaks can be found with `dump_live_allocations`.
+use core::alloc::Layout;
+use core::mem::{size_of, align_of};
+use core::ptr;
+
+use crate::sync::SpinLock;
+
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+// fresh objects are filled too, so reads of uninitialized memory stand out
+const UNINITIALIZED_BYTE: u8 = 0xcd;
+const FREED_BYTE: u8 = 0xdd;
+
+const LIVE_MAGIC: u64 = 0x6865_6170_6c69_7665;
+const FREED_MAGIC: u64 = 0x6865_6170_6672_6565;
+
+const CALLERS: usize = 6;
+const MAX_FRAMES: usize = 16;
+
+// `magic` is not the first field, the slab allocator reuses the first word of freed blocks
+#[repr(C)]
+struct Header {
+    id: u64,
+    magic: u64,
+    layout: Layout,
+    // from the start of the underlying allocation to the object
+    offset: usize,
+    callers: [usize; CALLERS],
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+struct LiveList {
+    head: *mut Header,
+    tail: *mut Header,
+    count: usize,
+    bytes: usize,
+    next_id: u64,
+}
+
+unsafe impl Send for LiveList {}
+
+static LIVE: SpinLock<LiveList> = SpinLock::new("live allocations", LiveList {
+    head: ptr::null_mut(),
+    tail: ptr::null_mut(),
+    count: 0,
+    bytes: 0,
+    next_id: 0,
+});
+
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    pub id: u64,
+    pub address: usize,
+    pub size: usize,
+    pub align: usize,
+    pub callers: [usize; CALLERS],
+}
+
+fn object_offset(layout: &Layout) -> usize {
+    let align = layout.align();
+    return (size_of::<Header>() + RED_ZONE + align - 1) & !(align - 1);
+}
+
+fn underlying_layout(layout: &Layout) -> Layout {
+    let align = core::cmp::max(layout.align(), align_of::<Header>());
+    let size = object_offset(layout) + layout.size() + RED_ZONE;
+    return Layout::from_size_align(size, align).unwrap();
+}
+
+// Return addresses found by following the frame pointers. Without them rbp is just another
+// register, so no call sites are recorded (`build.rs` sets the cfg).
+#[cfg(frame_pointers)]
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    use core::arch::asm;
+    use crate::memory::PHYSICAL_MEMORY_OFFSET;
+
+    let mut callers = [0; CALLERS];
+
+    let mut frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack)) };
+
+    let mut index = 0;
+    for _ in 0..MAX_FRAMES {
+        if index == CALLERS || frame < PHYSICAL_MEMORY_OFFSET || frame % 8 != 0 {
+            break;
+        }
+
+        let (next, return_address) = unsafe { (*(frame as *const usize), *((frame + 8) as *const usize)) };
+        if return_address == 0 {
+            break;
+        }
+        callers[index] = return_address;
+        index += 1;
+
+        // frames of the callers are always above
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+
+    return callers;
+}
+
+#[cfg(not(frame_pointers))]
+fn callers() -> [usize; CALLERS] {
+    return [0; CALLERS];
+}
+
+unsafe fn red_zones_intact(header: *const Header, object: *const u8) -> bool {
+    let front = object.sub(RED_ZONE);
+    let back = object.add((*header).layout.size());
+
+    return (0..RED_ZONE).all(|index| *front.add(index) == RED_ZONE_BYTE && *back.add(index) == RED_ZONE_BYTE);
+}
+
+pub unsafe fn alloc(layout: Layout, raw_alloc: unsafe fn(Layout) -> *mut u8) -> *mut u8 {
+    let base = raw_alloc(underlying_layout(&layout));
+    if base.is_null() {
+        return base;
+    }
+
+    let offset = object_offset(&layout);
+    let object = base.add(offset);
+    let header = object.sub(RED_ZONE).sub(size_of::<Header>()) as *mut Header;
+
+    ptr::write_bytes(object.sub(RED_ZONE), RED_ZONE_BYTE, RED_ZONE);
+    ptr::write_bytes(object, UNINITIALIZED_BYTE, layout.size());
+    ptr::write_bytes(object.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+
+    let mut live = LIVE.lock();
+    header.write(Header {
+        magic: LIVE_MAGIC,
+        id: live.next_id,
+        layout: layout,
+        offset: offset,
+        callers: callers(),
+        prev: live.tail,
+        next: ptr::null_mut(),
+    });
+
+    if live.tail.is_null() {
+        live.head = header;
+    } else {
+        (*live.tail).next = header;
+    }
+    live.tail = header;
+    live.next_id += 1;
+    live.count += 1;
+    live.bytes += layout.size();
+
+    return object;
+}
+
+pub unsafe fn dealloc(object: *mut u8, layout: Layout, raw_dealloc: unsafe fn(*mut u8, Layout)) {
+    let header = object.sub(RED_ZONE).sub(size_of::<Header>()) as *mut Header;
+
+    match (*header).magic {
+        LIVE_MAGIC => {}
+        FREED_MAGIC => panic!("heap: double free of {:p} ({:?}), allocated from {:x?}", object, layout, (*header).callers),
+        _ => panic!("heap: free of {:p} ({:?}), which is not a heap object or its header is corrupted", object, layout),
+    }
+
+    assert!((*header).layout == layout, "heap: {:p} allocated as {:?} but freed as {:?}, allocated from {:x?}",
+            object, (*header).layout, layout, (*header).callers);
+    assert!(red_zones_intact(header, object), "heap: red zone of {:p} ({:?}) overwritten, allocated from {:x?}",
+            object, layout, (*header).callers);
+
+    {
+        let mut live = LIVE.lock();
+        if (*header).prev.is_null() {
+            live.head = (*header).next;
+        } else {
+            (*(*header).prev).next = (*header).next;
+        }
+        if (*header).next.is_null() {
+            live.tail = (*header).prev;
+        } else {
+            (*(*header).next).prev = (*header).prev;
+        }
+        live.count -= 1;
+        live.bytes -= layout.size();
+    }
+
+    (*header).magic = FREED_MAGIC;
+    ptr::write_bytes(object, FREED_BYTE, layout.size());
+
+    let base = object.sub((*header).offset);
+    raw_dealloc(base, underlying_layout(&layout));
+}
+
+// Number of live allocations and the bytes requested by them
+pub fn live_stats() -> (usize, usize) {
+    let live = LIVE.lock();
+    return (live.count, live.bytes);
+}
+
+// Calls `f` for every live allocation, oldest first. The list is copied in small batches,
+// so `f` can print (and allocate) without deadlocking on the list lock.
+pub fn for_each_live_allocation<F>(mut f: F) where F: FnMut(LiveAllocation) {
+    const BATCH: usize = 32;
+
+    let mut next_id = 0;
+    loop {
+        let mut batch = [None; BATCH];
+        let mut count = 0;
+
+        {
+            let live = LIVE.lock();
+            let mut header = live.head;
+            unsafe {
+                while !header.is_null() && count < BATCH {
+                    if (*header).id >= next_id {
+                        batch[count] = Some(LiveAllocation {
+                            id: (*header).id,
+                            address: header as usize + size_of::<Header>() + RED_ZONE,
+                            size: (*header).layout.size(),
+                            align: (*header).layout.align(),
+                            callers: (*header).callers,
+                        });
+                        count += 1;
+                    }
+                    header = (*header).next;
+                }
+            }
+        }
+
+        if count == 0 {
+            return;
+        }
+        for allocation in batch.iter().flatten() {
+            next_id = allocation.id + 1;
+            f(*allocation);
+        }
+    }
+}
+
+pub fn dump_live_allocations() {
+    let (count, bytes) = live_stats();
+    crate::println_all!("heap: {} live allocations, {} bytes", count, bytes);
+
+    for_each_live_allocation(|allocation| {
+        crate::println_all!("  #{} {:#x} size {} align {}, from {:x?}",
+                            allocation.id, allocation.address, allocation.size, allocation.align, allocation.callers);
+    });
+}
@@ -0,0 +1,51 @@
+// Kernel address space layout randomization for the kernel virtual regions. The kernel image
+// itself stays at KERNEL_OFFSET, it is not linked as position independent code.
+use crate::cmdline;
+use crate::drivers::random::{self, SplitMix64};
+use crate::memory::paging::VirtualAddress;
+
+// The regions are placed in separate windows of this range, so they can never overlap.
+// It starts 16 TiB above PHYSICAL_MEMORY_OFFSET, which leaves room for the physical memory map.
+const RANDOM_AREA_START: VirtualAddress = 0xffff_9000_0000_0000;
+const RANDOM_AREA_END: VirtualAddress = 0xffff_f000_0000_0000;
+const REGION_COUNT: usize = 3;
+const REGION_ALIGN: usize = 0x4000_0000; // 1 GiB
+
+// Layout used with `nokaslr`
+const FIXED_HEAP_START: VirtualAddress = 0xffff_a000_0000_0000;
+const FIXED_MAP_AREA_START: VirtualAddress = 0xffff_c000_0000_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelLayout {
+    pub heap_start: VirtualAddress,
+    pub stack_area_start: VirtualAddress,
+    pub map_area_start: VirtualAddress,
+    pub randomized: bool,
+}
+
+pub fn choose_layout(heap_size: usize, stack_area_size: usize, map_area_size: usize) -> KernelLayout {
+    if cmdline::flag("nokaslr") {
+        return KernelLayout {
+            heap_start: FIXED_HEAP_START,
+            stack_area_start: FIXED_HEAP_START + heap_size,
+            map_area_start: FIXED_MAP_AREA_START,
+            randomized: false,
+        };
+    }
+
+    let mut random = SplitMix64::new(random::hardware_seed());
+    let window = (RANDOM_AREA_END - RANDOM_AREA_START) / REGION_COUNT;
+
+    let mut place = |index: usize, size: usize| -> VirtualAddress {
+        assert!(size <= window, "kernel region of {:#x} bytes does not fit in the KASLR window", size);
+        let slots = (window - size) / REGION_ALIGN + 1;
+        return RANDOM_AREA_START + index * window + (random.next_u64() as usize % slots) * REGION_ALIGN;
+    };
+
+    return KernelLayout {
+        heap_start: place(0, heap_size),
+        stack_area_start: place(1, stack_area_size),
+        map_area_start: place(2, map_area_size),
+        randomized: true,
+    };
+}
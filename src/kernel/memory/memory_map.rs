@@ -0,0 +1,126 @@
+// Copy of the firmware memory map and of the ranges reserved at boot, kept for the boot report
+use multiboot2::BootInformation;
+
+use crate::memory::paging::PhysicalAddress;
+use crate::sync::SpinLock;
+
+const MAX_AREAS: usize = 128;
+const MAX_RESERVATIONS: usize = 32;
+
+const MEMORY_MAP_TAG: u32 = 6;
+const END_TAG: u32 = 0;
+// base address, length and type, newer versions of the specification may append fields
+const MIN_ENTRY_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaType {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Bad,
+    Unknown(u32),
+}
+
+impl AreaType {
+    fn from_multiboot(typ: u32) -> AreaType {
+        return match typ {
+            1 => AreaType::Usable,
+            2 => AreaType::Reserved,
+            3 => AreaType::AcpiReclaimable,
+            4 => AreaType::AcpiNvs,
+            5 => AreaType::Bad,
+            typ => AreaType::Unknown(typ),
+        };
+    }
+
+    pub fn name(self) -> &'static str {
+        return match self {
+            AreaType::Usable => "usable",
+            AreaType::Reserved => "reserved",
+            AreaType::AcpiReclaimable => "ACPI reclaimable",
+            AreaType::AcpiNvs => "ACPI NVS",
+            AreaType::Bad => "bad",
+            AreaType::Unknown(_) => "unknown",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    pub start: PhysicalAddress,
+    pub end: PhysicalAddress,
+    pub typ: AreaType,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub name: &'static str,
+    pub start: PhysicalAddress,
+    pub end: PhysicalAddress,
+}
+
+static AREAS: SpinLock<[Option<MemoryArea>; MAX_AREAS]> = SpinLock::new("memory areas", [None; MAX_AREAS]);
+static RESERVATIONS: SpinLock<[Option<Reservation>; MAX_RESERVATIONS]> = SpinLock::new("boot reservations", [None; MAX_RESERVATIONS]);
+
+// The memory map tag is parsed by hand, because the multiboot2 crate iterates only over usable areas
+pub fn record(boot_info: &BootInformation) {
+    let mut areas = AREAS.lock();
+    let mut count = 0;
+
+    unsafe {
+        let mut tag = boot_info.start_address() + 8;
+        while tag < boot_info.end_address() {
+            let typ = *(tag as *const u32);
+            let size = *((tag + 4) as *const u32) as usize;
+            if typ == END_TAG {
+                break;
+            }
+
+            if typ == MEMORY_MAP_TAG {
+                let entry_size = *((tag + 8) as *const u32) as usize;
+                assert!(entry_size >= MIN_ENTRY_SIZE, "memory map entries are too small ({} bytes)", entry_size);
+                let mut entry = tag + 16;
+                while entry + entry_size <= tag + size && count < MAX_AREAS {
+                    let start = *(entry as *const u64) as usize;
+                    let length = *((entry + 8) as *const u64) as usize;
+                    let typ = *((entry + 16) as *const u32);
+
+                    areas[count] = Some(MemoryArea {
+                        start: start,
+                        end: start + length,
+                        typ: AreaType::from_multiboot(typ),
+                    });
+                    count += 1;
+                    entry += entry_size;
+                }
+            }
+
+            // tags are 8 byte aligned
+            tag += (size + 7) & !7;
+        }
+    }
+}
+
+// `end` is exclusive
+pub fn reserve(name: &'static str, start: PhysicalAddress, end: PhysicalAddress) {
+    let mut reservations = RESERVATIONS.lock();
+    match reservations.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(Reservation { name: name, start: start, end: end }),
+        None => panic!("too many boot reservations, cannot record {}", name),
+    }
+}
+
+pub fn for_each_area<F>(mut f: F) where F: FnMut(MemoryArea) {
+    let areas = *AREAS.lock();
+    for area in areas.iter().flatten() {
+        f(*area);
+    }
+}
+
+pub fn for_each_reservation<F>(mut f: F) where F: FnMut(Reservation) {
+    let reservations = *RESERVATIONS.lock();
+    for reservation in reservations.iter().flatten() {
+        f(*reservation);
+    }
+}
@@ -1,16 +1,36 @@
-mod area_frame_allocator;
+mod bitmap_frame_allocator;
 mod paging;
 pub mod allocator;
 mod stack_allocator;
+pub mod slab;
+pub mod fault;
+pub mod vma;
+pub mod dma;
+pub mod memory_map;
+mod kaslr;
+#[cfg(feature = "heap-debug")]
+pub mod heap_debug;
 
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use multiboot2::BootInformation;
+use spin::Once;
 
-pub use self::area_frame_allocator::AreaFrameAllocator;
-use self::paging::PhysicalAddress;
-pub use self::paging::remap_the_kernel;
+use crate::cmdline;
+use crate::modules::MAX_MODULES;
+use crate::sync::{SpinLock, SpinLockGuard};
+
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::paging::{Page, EntryFlags, PhysicalAddress, VirtualAddress};
 pub use self::stack_allocator::Stack;
 
-pub fn init(boot_info: BootInformation) -> MemoryController {
+static MEMORY_CONTROLLER: Once<SpinLock<MemoryController>> = Once::new();
+
+// Overridable with `kstack_slots=` and `kstack_max_pages=` on the kernel command line
+const DEFAULT_KERNEL_STACK_SLOTS: usize = 256;
+const DEFAULT_KERNEL_STACK_MAX_PAGES: usize = 16;
+
+pub fn init(boot_info: &BootInformation) {
     assert_has_not_been_called!("memory::init can be called only once");
 
     enable_nxe_bit();
@@ -19,40 +39,141 @@ pub fn init(boot_info: BootInformation) -> MemoryController {
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
     let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf-sections tag required");
 
-    let kernel_start = elf_sections_tag.sections().map(|s| s.start_address()).min().unwrap();
-    let kernel_end = elf_sections_tag.sections().map(|s| s.end_address()).max().unwrap();
+    let kernel_start = elf_sections_tag.sections().filter(|s| s.is_allocated())
+        .map(|s| kernel_physical_address(s.start_address() as usize)).min().unwrap();
+    let kernel_end = elf_sections_tag.sections().filter(|s| s.is_allocated())
+        .map(|s| kernel_physical_address(s.end_address() as usize)).max().unwrap();
 
-    let mut frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize, kernel_end as usize, boot_info.start_address(),
-        boot_info.end_address(), memory_map_tag.memory_areas()
-    );
+    memory_map::record(boot_info);
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, &boot_info);
+    let multiboot_start = virt_to_phys(boot_info.start_address());
+    let multiboot_end = virt_to_phys(boot_info.end_address());
+    memory_map::reserve("kernel", kernel_start, kernel_end);
+    memory_map::reserve("multiboot information", multiboot_start, multiboot_end);
 
-    use self::paging::Page;
-    use self::allocator::{HEAP_START, HEAP_SIZE};
-
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE-1);
+    // modules loaded by the bootloader stay where they are, see `modules`
+    let module_count = boot_info.module_tags().count();
+    assert!(module_count <= MAX_MODULES, "{} boot modules, at most {} are supported", module_count, MAX_MODULES);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::EntryFlags::WRITABLE, &mut frame_allocator);
+    let mut reserved = [(0, 0); 2 + MAX_MODULES];
+    reserved[0] = (kernel_start, kernel_end);
+    reserved[1] = (multiboot_start, multiboot_end);
+    let mut reserved_count = 2;
+    for module in boot_info.module_tags() {
+        reserved[reserved_count] = (module.start_address() as usize, module.end_address() as usize);
+        reserved_count += 1;
+        // named like in `modules`, after the first word of the command line
+        let name = module.name().split_whitespace().next().unwrap_or("module");
+        memory_map::reserve(name, module.start_address() as usize, module.end_address() as usize);
     }
 
-    allocator::init();
+    let mut frame_allocator = BitmapFrameAllocator::new(&reserved[..reserved_count], memory_map_tag.memory_areas());
+
+    let (metadata_start, metadata_end) = frame_allocator.metadata_frames();
+    memory_map::reserve("frame bitmap", metadata_start.start_address(), metadata_end.start_address() + PAGE_SIZE);
+
+    let active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
+
+    use self::paging::Page;
+    use self::stack_allocator::StackAllocator;
+
+    let slot_count = cmdline::value_usize("kstack_slots").unwrap_or(DEFAULT_KERNEL_STACK_SLOTS);
+    let max_stack_pages = cmdline::value_usize("kstack_max_pages").unwrap_or(DEFAULT_KERNEL_STACK_MAX_PAGES);
+
+    // `nokaslr` on the command line keeps the regions at fixed addresses
+    let layout = kaslr::choose_layout(allocator::HEAP_MAX_SIZE, StackAllocator::area_size(slot_count, max_stack_pages), MAP_AREA_SIZE);
+    allocator::init(layout.heap_start);
 
-    let stack_allocator = {
-        let stack_alloc_start = heap_end_page + 1;
-        let stack_alloc_end = stack_alloc_start + 100;
-        let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
-        stack_allocator::StackAllocator::new(stack_alloc_range)
-    };
+    let stack_allocator = StackAllocator::new(Page::containing_address(layout.stack_area_start), slot_count, max_stack_pages);
 
-    return MemoryController {
+    MEMORY_CONTROLLER.call_once(|| SpinLock::new("memory controller", MemoryController {
         active_table: active_table,
         frame_allocator: frame_allocator,
         stack_allocator: stack_allocator,
-    };
+        map_area_start: layout.map_area_start,
+        map_area_next: layout.map_area_start,
+        kaslr: layout.randomized,
+    }));
+}
+
+pub fn controller() -> SpinLockGuard<'static, MemoryController> {
+    return MEMORY_CONTROLLER.get().expect("memory is not initialized").lock();
+}
+
+// For the fault handler, which must not spin on a lock held by the code it interrupted
+pub fn try_controller() -> Option<SpinLockGuard<'static, MemoryController>> {
+    return MEMORY_CONTROLLER.get()?.try_lock();
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub page_table_frames: usize,
+    pub heap_mapped: usize,
+    pub slab_bytes: usize,
+    pub slab_in_use: usize,
+    pub kernel_stacks: usize,
+    pub kernel_stack_pages: usize,
+}
+
+// All of the counters at once, sizes are in bytes
+pub fn stats() -> MemoryStats {
+    return collect_stats(|| Some(controller())).unwrap();
+}
+
+// For the allocation error handler, which can run with the controller locked
+pub fn try_stats() -> Option<MemoryStats> {
+    return collect_stats(try_controller);
+}
+
+// The controller is locked last, the allocator locks come before it
+fn collect_stats<F>(lock_controller: F) -> Option<MemoryStats> where F: FnOnce() -> Option<SpinLockGuard<'static, MemoryController>> {
+    let mut slab_bytes = 0;
+    let mut slab_in_use = 0;
+    allocator::for_each_cache(|cache| {
+        slab_bytes += cache.slabs * cache.slab_size;
+        slab_in_use += cache.objects_in_use * cache.object_size;
+    });
+
+    let heap = allocator::heap_stats();
+    let controller = lock_controller()?;
+
+    return Some(MemoryStats {
+        total_frames: controller.frame_allocator.total_frames(),
+        free_frames: controller.frame_allocator.free_frames(),
+        page_table_frames: paging::table_frames(),
+        heap_mapped: heap.mapped,
+        slab_bytes: slab_bytes,
+        slab_in_use: slab_in_use,
+        kernel_stacks: controller.stack_allocator.used_stacks(),
+        kernel_stack_pages: controller.stack_allocator.mapped_pages(),
+    });
+}
+
+impl fmt::Display for MemoryStats {
+    // In the format of /proc/meminfo
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kib = |bytes: usize| bytes / 1024;
+
+        writeln!(f, "MemTotal:       {:>10} kB", kib(self.total_frames * PAGE_SIZE))?;
+        writeln!(f, "MemFree:        {:>10} kB", kib(self.free_frames * PAGE_SIZE))?;
+        writeln!(f, "Heap:           {:>10} kB", kib(self.heap_mapped))?;
+        writeln!(f, "Slab:           {:>10} kB", kib(self.slab_bytes))?;
+        writeln!(f, "SlabInUse:      {:>10} kB", kib(self.slab_in_use))?;
+        writeln!(f, "KernelStack:    {:>10} kB", kib(self.kernel_stack_pages * PAGE_SIZE))?;
+        writeln!(f, "KernelStacks:   {:>10}", self.kernel_stacks)?;
+        write!(f, "PageTables:     {:>10} kB", kib(self.page_table_frames * PAGE_SIZE))
+    }
+}
+
+// The kernel image is mapped at KERNEL_OFFSET, except for the boot trampoline
+fn kernel_physical_address(address: VirtualAddress) -> PhysicalAddress {
+    if address >= KERNEL_OFFSET {
+        return address - KERNEL_OFFSET;
+    } else {
+        return address;
+    }
 }
 
 fn enable_nxe_bit() {
@@ -73,6 +194,32 @@ fn enable_write_protect_bit() {
 
 pub const PAGE_SIZE: usize = 4096;
 
+// Start of the kernel image in the higher half, the lower half is left for user processes
+pub const KERNEL_OFFSET: VirtualAddress = 0xffff_ffff_8000_0000;
+
+// All physical memory is linearly mapped here (only the first GiB until the kernel is remapped)
+pub const PHYSICAL_MEMORY_OFFSET: VirtualAddress = 0xffff_8000_0000_0000;
+
+// The boot page tables map only this much of physical memory at PHYSICAL_MEMORY_OFFSET
+pub const BOOT_MAPPED_LIMIT: PhysicalAddress = 0x4000_0000;
+
+// End of the physical memory map, raised by `remap_the_kernel` when it maps all of physical memory
+static PHYSICAL_MEMORY_MAPPED: AtomicUsize = AtomicUsize::new(BOOT_MAPPED_LIMIT);
+
+// Physical ranges outside of the frame allocator (modules, MMIO, firmware tables) get mapped in this area
+pub const MAP_AREA_SIZE: usize = 0o_000_100_000_000_0000; // 64 GiB
+
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    return PHYSICAL_MEMORY_OFFSET + address;
+}
+
+pub fn virt_to_phys(address: VirtualAddress) -> PhysicalAddress {
+    let end = PHYSICAL_MEMORY_OFFSET + PHYSICAL_MEMORY_MAPPED.load(Ordering::Relaxed);
+    assert!(address >= PHYSICAL_MEMORY_OFFSET && address < end,
+            "{:#x} is not in the physical memory map", address);
+    return address - PHYSICAL_MEMORY_OFFSET;
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     number: usize,
@@ -87,6 +234,10 @@ impl Frame {
         return self.number * PAGE_SIZE;
     }
 
+    pub fn virtual_address(&self) -> VirtualAddress {
+        return phys_to_virt(self.start_address());
+    }
+
     fn clone(&self) -> Frame {
         return Frame { number: self.number };
     }
@@ -101,7 +252,13 @@ impl Frame {
 
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
+    // Physically contiguous frames, aligned to `alignment` bytes and ending at or below `max_address`
+    fn allocate_contiguous(&mut self, count: usize, alignment: usize, max_address: PhysicalAddress) -> Option<Frame>;
+    // Drops a reference, the frame is freed once nothing refers to it
     fn deallocate_frame(&mut self, frame: Frame);
+    // Adds a reference to an allocated frame
+    fn share_frame(&mut self, frame: &Frame);
+    fn frame_references(&self, frame: &Frame) -> usize;
 }
 
 struct FrameIter {
@@ -125,13 +282,120 @@ impl Iterator for FrameIter {
 
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
     stack_allocator: stack_allocator::StackAllocator,
+    map_area_start: VirtualAddress,
+    map_area_next: VirtualAddress,
+    kaslr: bool,
 }
 
 impl MemoryController {
+    pub fn kaslr_enabled(&self) -> bool {
+        return self.kaslr;
+    }
+
     pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
-        let &mut MemoryController { ref mut active_table, ref mut frame_allocator, ref mut stack_allocator } = self;
+        let &mut MemoryController { ref mut active_table, ref mut frame_allocator, ref mut stack_allocator, .. } = self;
         stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
     }
+
+    pub fn free_stack(&mut self, stack: Stack) {
+        let &mut MemoryController { ref mut active_table, ref mut frame_allocator, ref mut stack_allocator, .. } = self;
+        stack_allocator.free_stack(active_table, frame_allocator, stack)
+    }
+
+    // Maps fresh frames to the given range, nothing is left mapped if physical memory runs out
+    pub fn map_range(&mut self, start: VirtualAddress, size: usize) -> bool {
+        use self::paging::{Page, EntryFlags};
+
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(start + size - 1);
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = match self.frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    for mapped_page in Page::range_inclusive(start_page, end_page).take_while(|&p| p < page) {
+                        self.active_table.unmap(mapped_page, &mut self.frame_allocator);
+                    }
+                    return false;
+                }
+            };
+            self.active_table.map_to(page, frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut self.frame_allocator);
+        }
+
+        return true;
+    }
+
+    // Maps physical memory which the frame allocator does not hand out, the returned address keeps
+    // the offset of `start` in its page. The virtual space is never reused.
+    pub fn map_physical(&mut self, start: PhysicalAddress, size: usize, flags: EntryFlags) -> Option<VirtualAddress> {
+        let first_frame = Frame::containing_address(start);
+        let last_frame = Frame::containing_address(start + size - 1);
+        let page_count = last_frame.number - first_frame.number + 1;
+
+        if self.map_area_next + page_count * PAGE_SIZE > self.map_area_start + MAP_AREA_SIZE {
+            return None;
+        }
+        let first_page = Page::containing_address(self.map_area_next);
+        self.map_area_next += page_count * PAGE_SIZE;
+
+        for (index, frame) in Frame::range_inclusive(first_frame, last_frame).enumerate() {
+            self.active_table.map_to(first_page + index, frame, flags, &mut self.frame_allocator);
+        }
+
+        return Some(first_page.start_address() + start % PAGE_SIZE);
+    }
+
+    pub fn unmap_physical(&mut self, start: VirtualAddress, size: usize) {
+        for page in Page::range_inclusive(Page::containing_address(start), Page::containing_address(start + size - 1)) {
+            self.active_table.unmap_keep_frame(page, &mut self.frame_allocator);
+        }
+    }
+
+    // Maps a fresh frame, `fill` initializes it through the physical memory map before it becomes visible
+    pub fn map_filled<F>(&mut self, page: Page, flags: EntryFlags, fill: F) -> bool where F: FnOnce(&mut [u8]) {
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        fill(unsafe { core::slice::from_raw_parts_mut(frame.virtual_address() as *mut u8, PAGE_SIZE) });
+        self.active_table.map_to(page, frame, flags, &mut self.frame_allocator);
+        return true;
+    }
+
+    pub fn is_mapped(&self, page: Page) -> bool {
+        return self.active_table.translate_page(page).is_some();
+    }
+
+    pub fn unmap_page(&mut self, page: Page) {
+        self.active_table.unmap(page, &mut self.frame_allocator);
+    }
+
+    // Maps `target` to the frame of `source`, both pages become copy-on-write if `source` is writable
+    pub fn share_copy_on_write(&mut self, source: Page, target: Page) -> bool {
+        let (frame, flags) = match self.active_table.share_copy_on_write(source, &mut self.frame_allocator) {
+            Some(shared) => shared,
+            None => return false,
+        };
+
+        self.active_table.map_to(target, frame, flags, &mut self.frame_allocator);
+        return true;
+    }
+
+    pub fn resolve_copy_on_write(&mut self, page: Page) -> bool {
+        return self.active_table.resolve_copy_on_write(page, &mut self.frame_allocator);
+    }
+
+    pub fn unmap_range(&mut self, start: VirtualAddress, size: usize) {
+        use self::paging::Page;
+
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(start + size - 1);
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            self.active_table.unmap(page, &mut self.frame_allocator);
+        }
+    }
 }
@@ -0,0 +1,490 @@
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use super::{Page, PageSize, VirtualAddress, PhysicalAddress, InactivePageTable, ENTRY_COUNT, KERNEL_P4_INDEX};
+use super::entry::*;
+use super::mapper::Mapper;
+use super::table::{Table, Level4, free_table};
+use crate::memory::{self, PAGE_SIZE, Frame, FrameAllocator, MemoryController};
+use crate::memory::slab::{ObjectCache, CacheBox};
+use crate::memory::vma::{self, Vma, VmaTree, VmError, Protection, MapFlags, Backing, SharedPages, MMAP_MIN_ADDRESS, USER_END};
+
+// Lower half of the virtual address space owned by a process. The kernel half of the P4
+// is copied from the kernel table, so its P3 tables (and everything below) are shared.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    user_pages: usize,
+    vmas: VmaTree,
+    brk_start: VirtualAddress,
+    brk: VirtualAddress,
+}
+
+// One per process, allocated (and freed) on every fork and exit
+static ADDRESS_SPACES: ObjectCache<AddressSpace> = ObjectCache::new("address_space");
+
+// The address space loaded into CR3, null while only the kernel table is. Address spaces always
+// live in the cache, so the pointer stays valid until the drop clears it.
+static CURRENT: AtomicPtr<AddressSpace> = AtomicPtr::new(ptr::null_mut());
+
+fn page_align_up(address: usize) -> Option<usize> {
+    return address.checked_add(PAGE_SIZE - 1).map(|address| address & !(PAGE_SIZE - 1));
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<CacheBox<AddressSpace>> {
+        let mut controller = memory::controller();
+
+        let frame = controller.frame_allocator.allocate_frame()?;
+        let table = InactivePageTable::new(frame);
+
+        let kernel_table = unsafe { Mapper::new(controller.active_table.kernel_p4.clone()) };
+        let p4 = unsafe { &mut *(table.p4_frame.virtual_address() as *mut Table<Level4>) };
+        for index in KERNEL_P4_INDEX..ENTRY_COUNT {
+            let frame = kernel_table.p4()[index].pointed_frame().expect("kernel half P3 table missing");
+            p4[index].set(frame, kernel_table.p4()[index].flags());
+        }
+
+        // the cache may have to map a new slab, which needs the controller
+        drop(controller);
+
+        // if that fails, dropping the AddressSpace frees the table again
+        return ADDRESS_SPACES.alloc(AddressSpace {
+            table: table,
+            user_pages: 0,
+            vmas: VmaTree::new(),
+            brk_start: 0,
+            brk: 0,
+        });
+    }
+
+    fn mapper(&self) -> Mapper {
+        return unsafe { Mapper::new(self.table.p4_frame.clone()) };
+    }
+
+    pub fn user_pages(&self) -> usize {
+        return self.user_pages;
+    }
+
+    pub fn is_active(&self) -> bool {
+        return memory::controller().active_table.is_loaded(&self.table);
+    }
+
+    // Loads the table into CR3, called on a context switch
+    pub fn activate(&self) {
+        let mut controller = memory::controller();
+        unsafe { controller.active_table.load(&self.table) };
+        CURRENT.store(self as *const AddressSpace as *mut AddressSpace, Ordering::Relaxed);
+    }
+
+    // Called by the page fault handler for faults in the user half, false if there is no address space
+    pub fn handle_current_page_fault(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+        let current = CURRENT.load(Ordering::Relaxed);
+        if current.is_null() {
+            return false;
+        }
+        // the kernel never touches user memory while it is changing the mappings
+        return unsafe { (*current).handle_page_fault(address, error_code) };
+    }
+
+    // Maps a zeroed frame, USER_ACCESSIBLE is always added
+    pub fn map(&mut self, page: Page, flags: EntryFlags) -> bool {
+        assert!(page.p4_index() < KERNEL_P4_INDEX, "page {:?} is not in the user half", page);
+
+        let mut controller = memory::controller();
+        let frame = match controller.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe { core::ptr::write_bytes(frame.virtual_address() as *mut u8, 0, PAGE_SIZE) };
+
+        self.mapper().map_to(page, frame, flags | EntryFlags::USER_ACCESSIBLE, &mut controller.frame_allocator);
+        self.user_pages += 1;
+        return true;
+    }
+
+    pub fn unmap(&mut self, page: Page) {
+        assert!(page.p4_index() < KERNEL_P4_INDEX, "page {:?} is not in the user half", page);
+
+        let mut controller = memory::controller();
+        self.mapper().unmap(page, &mut controller.frame_allocator);
+        self.user_pages -= 1;
+    }
+
+    pub fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        return self.mapper().translate(address);
+    }
+
+    pub fn vmas(&self) -> &VmaTree {
+        return &self.vmas;
+    }
+
+    // Pages of a mapping are populated on the first access, see `handle_page_fault`
+    pub fn mmap(&mut self, address: VirtualAddress, length: usize, protection: Protection, flags: MapFlags, backing: Backing) -> Result<VirtualAddress, VmError> {
+        if length == 0 || flags.contains(MapFlags::SHARED) == flags.contains(MapFlags::PRIVATE) {
+            return Err(VmError::InvalidArgument);
+        }
+        match backing {
+            Backing::Anonymous if !flags.contains(MapFlags::ANONYMOUS) => return Err(VmError::InvalidArgument),
+            Backing::File { .. } if flags.contains(MapFlags::ANONYMOUS) => return Err(VmError::InvalidArgument),
+            Backing::File { offset, .. } if offset % PAGE_SIZE != 0 => return Err(VmError::InvalidArgument),
+            _ => {}
+        }
+
+        let length = page_align_up(length).ok_or(VmError::OutOfMemory)?;
+        if length > USER_END - MMAP_MIN_ADDRESS {
+            return Err(VmError::OutOfMemory);
+        }
+
+        let start = if flags.intersects(MapFlags::FIXED | MapFlags::FIXED_NOREPLACE) {
+            if address % PAGE_SIZE != 0 || address < MMAP_MIN_ADDRESS {
+                return Err(VmError::InvalidArgument);
+            }
+            if address > USER_END - length {
+                return Err(VmError::OutOfMemory);
+            }
+
+            if !self.vmas.is_free(address, address + length) {
+                if flags.contains(MapFlags::FIXED_NOREPLACE) {
+                    return Err(VmError::AlreadyExists);
+                }
+                self.remove_range(address, address + length);
+            }
+            address
+        } else {
+            // the address is only a hint, it is used if the range is free
+            let hint = address & !(PAGE_SIZE - 1);
+            if hint >= MMAP_MIN_ADDRESS && hint <= USER_END - length && self.vmas.is_free(hint, hint + length) {
+                hint
+            } else {
+                self.vmas.find_free(length).ok_or(VmError::OutOfMemory)?
+            }
+        };
+
+        self.vmas.insert(Vma {
+            start: start,
+            end: start + length,
+            protection: protection,
+            shared: if flags.contains(MapFlags::SHARED) { Some(SharedPages::new()) } else { None },
+            backing: backing,
+        });
+        return Ok(start);
+    }
+
+    pub fn munmap(&mut self, address: VirtualAddress, length: usize) -> Result<(), VmError> {
+        let length = page_align_up(length).ok_or(VmError::InvalidArgument)?;
+        if address % PAGE_SIZE != 0 || length == 0 || address >= USER_END || length > USER_END - address {
+            return Err(VmError::InvalidArgument);
+        }
+
+        self.remove_range(address, address + length);
+        return Ok(());
+    }
+
+    // The whole range has to be mapped, populated pages get their new flags right away
+    pub fn mprotect(&mut self, address: VirtualAddress, length: usize, protection: Protection) -> Result<(), VmError> {
+        let length = page_align_up(length).ok_or(VmError::OutOfMemory)?;
+        if address % PAGE_SIZE != 0 {
+            return Err(VmError::InvalidArgument);
+        }
+        if length == 0 {
+            return Ok(());
+        }
+        if address >= USER_END || length > USER_END - address {
+            return Err(VmError::OutOfMemory);
+        }
+
+        self.vmas.protect_range(address, address + length, protection)?;
+
+        let mut mapper = self.mapper();
+        let new_flags = protection.entry_flags();
+        for page in Page::range_inclusive(Page::containing_address(address), Page::containing_address(address + length - 1)) {
+            if let Some(flags) = mapper.page_flags(page) {
+                // shared frames stay read only until the write fault copies them
+                if flags.contains(EntryFlags::COPY_ON_WRITE) {
+                    mapper.update_flags(page, (new_flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE);
+                } else {
+                    mapper.update_flags(page, new_flags);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Set by the program loader to the page aligned end of the data segment
+    pub fn set_brk_start(&mut self, address: VirtualAddress) {
+        assert!(address % PAGE_SIZE == 0);
+        self.brk_start = address;
+        self.brk = address;
+    }
+
+    // Like the Linux syscall, returns the new break on success and the current one on failure
+    pub fn brk(&mut self, new_brk: VirtualAddress) -> VirtualAddress {
+        if new_brk < self.brk_start || new_brk >= USER_END {
+            return self.brk;
+        }
+
+        let old_end = page_align_up(self.brk).unwrap();
+        let new_end = page_align_up(new_brk).unwrap();
+
+        if new_end < old_end {
+            self.remove_range(new_end, old_end);
+        } else if new_end > old_end {
+            if !self.vmas.is_free(old_end, new_end) {
+                return self.brk;
+            }
+            self.vmas.insert(Vma {
+                start: old_end,
+                end: new_end,
+                protection: Protection::READ | Protection::WRITE,
+                shared: None,
+                backing: Backing::Anonymous,
+            });
+        }
+
+        self.brk = new_brk;
+        return self.brk;
+    }
+
+    // Removes the VMAs in the range and frees the pages populated in them
+    fn remove_range(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        // the removed VMAs are collected into a Vec, which must not be allocated with the controller locked
+        let removed = self.vmas.remove_range(start, end);
+
+        // locked after `removed`, so it is unlocked again before the last reference to a shared object goes
+        let mut controller = memory::controller();
+        let mut mapper = self.mapper();
+
+        for vma in removed.iter() {
+            for page in Page::range_inclusive(Page::containing_address(vma.start), Page::containing_address(vma.end - 1)) {
+                if mapper.page_flags(page).is_some() {
+                    mapper.unmap(page, &mut controller.frame_allocator);
+                    self.user_pages -= 1;
+                }
+            }
+        }
+    }
+
+    // Called for faults in the user half while this address space is active, returns false if the
+    // access is not allowed by the VMA (the process gets SIGSEGV then)
+    pub fn handle_page_fault(&mut self, address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+        let vma = match self.vmas.find(address) {
+            Some(vma) => vma.clone(),
+            None => return false,
+        };
+
+        if vma.protection.is_empty() {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.protection.contains(Protection::WRITE) {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !vma.protection.contains(Protection::EXEC) {
+            return false;
+        }
+
+        let page = Page::containing_address(address);
+
+        if let Some(pages) = vma.shared.as_ref() {
+            if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                return self.populate_shared(&vma, pages, page);
+            }
+        }
+
+        let mut controller = match memory::try_controller() {
+            Some(controller) => controller,
+            None => return false,
+        };
+        let mut mapper = self.mapper();
+
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && mapper.resolve_copy_on_write(page, &mut controller.frame_allocator);
+        }
+
+        return self.populate(&mut controller, &vma, page);
+    }
+
+    // Maps a frame filled from the backing of the VMA
+    fn populate(&mut self, controller: &mut MemoryController, vma: &Vma, page: Page) -> bool {
+        let frame = match AddressSpace::fill_frame(controller, vma, page) {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        self.mapper().map_to(page, frame, vma.entry_flags(), &mut controller.frame_allocator);
+        self.user_pages += 1;
+        return true;
+    }
+
+    // Maps the frame the shared object of the VMA has for the page, the first fault on the page
+    // (in any address space mapping the object) adds a new frame to the object
+    fn populate_shared(&mut self, vma: &Vma, pages: &SharedPages, page: Page) -> bool {
+        let key = pages.key(page.start_address() - vma.start);
+        let mut frames = match vma::try_shared_frames() {
+            Some(frames) => frames,
+            None => return false,
+        };
+
+        let frame = match frames.get(&key) {
+            Some(frame) => frame.clone(),
+            None => {
+                let frame = match memory::try_controller() {
+                    Some(mut controller) => AddressSpace::fill_frame(&mut controller, vma, page),
+                    None => None,
+                };
+                let frame = match frame {
+                    Some(frame) => frame,
+                    None => return false,
+                };
+                // this allocates, so the controller has to be unlocked again
+                frames.insert(key, frame.clone());
+                frame
+            }
+        };
+
+        let mut controller = match memory::try_controller() {
+            Some(controller) => controller,
+            None => return false,
+        };
+        // the object keeps its own reference
+        controller.frame_allocator.share_frame(&frame);
+        self.mapper().map_to(page, frame, vma.entry_flags(), &mut controller.frame_allocator);
+        self.user_pages += 1;
+        return true;
+    }
+
+    fn fill_frame(controller: &mut MemoryController, vma: &Vma, page: Page) -> Option<Frame> {
+        let frame = controller.frame_allocator.allocate_frame()?;
+
+        let buffer = unsafe { core::slice::from_raw_parts_mut(frame.virtual_address() as *mut u8, PAGE_SIZE) };
+        match vma.backing_at(page.start_address()) {
+            Backing::Anonymous => buffer.fill(0),
+            Backing::File { source, offset } => source.read_page(offset, buffer),
+        }
+        return Some(frame);
+    }
+
+    // Copy of the user half for `fork`. Pages of shared VMAs are shared as they are, all the other
+    // pages become copy-on-write in both address spaces (even read only ones, mprotect may change that).
+    // Unpopulated pages of shared VMAs stay that way, the shared object of the VMA hands the same
+    // frame to whichever address space faults on them first.
+    pub fn duplicate(&mut self) -> Option<CacheBox<AddressSpace>> {
+        use x86_64::instructions::tlb;
+
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+        child.brk_start = self.brk_start;
+        child.brk = self.brk;
+
+        let mut controller = memory::controller();
+
+        let mut child_mapper = child.mapper();
+        let mut mapper = self.mapper();
+        let vmas = &self.vmas;
+
+        for_each_user_page(&mut mapper, |page, entry| {
+            let frame = entry.pointed_frame().unwrap();
+            let mut flags = entry.flags();
+
+            let shared = vmas.find(page.start_address()).map_or(false, |vma| vma.is_shared());
+            if !shared {
+                flags = (flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE;
+                entry.set(frame.clone(), flags);
+            }
+
+            controller.frame_allocator.share_frame(&frame);
+            child_mapper.map_to(page, frame, flags, &mut controller.frame_allocator);
+        });
+        child.user_pages = self.user_pages;
+
+        // the pages which were just made read only may still be writable in the TLB
+        if controller.active_table.is_loaded(&self.table) {
+            tlb::flush_all();
+        }
+
+        return Some(child);
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees every user frame (or drops a reference to it, if it is shared) and every user page table
+    fn drop(&mut self) {
+        let mut controller = memory::controller();
+        if controller.active_table.is_loaded(&self.table) {
+            controller.active_table.load_kernel();
+            CURRENT.store(ptr::null_mut(), Ordering::Relaxed);
+        }
+
+        let allocator = &mut controller.frame_allocator;
+        let p4 = unsafe { &mut *(self.table.p4_frame.virtual_address() as *mut Table<Level4>) };
+
+        for p4_index in 0..KERNEL_P4_INDEX {
+            let p3 = match p4.next_table_mut(p4_index) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            for p3_index in 0..ENTRY_COUNT {
+                // huge pages point to their frames, not to a table
+                if p3[p3_index].flags().contains(EntryFlags::HUGE_PAGE) {
+                    allocator.deallocate_frames(p3[p3_index].pointed_frame().unwrap(), PageSize::Size1GiB.page_count());
+                    continue;
+                }
+                let p2 = match p3.next_table_mut(p3_index) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                for p2_index in 0..ENTRY_COUNT {
+                    if p2[p2_index].flags().contains(EntryFlags::HUGE_PAGE) {
+                        allocator.deallocate_frames(p2[p2_index].pointed_frame().unwrap(), PageSize::Size2MiB.page_count());
+                        continue;
+                    }
+                    let p1 = match p2.next_table_mut(p2_index) {
+                        Some(p1) => p1,
+                        None => continue,
+                    };
+                    for p1_index in 0..ENTRY_COUNT {
+                        if let Some(frame) = p1[p1_index].pointed_frame() {
+                            allocator.deallocate_frame(frame);
+                        }
+                    }
+                    free_table(p2[p2_index].pointed_frame().unwrap(), allocator);
+                }
+                free_table(p3[p3_index].pointed_frame().unwrap(), allocator);
+            }
+            free_table(p4[p4_index].pointed_frame().unwrap(), allocator);
+        }
+
+        free_table(self.table.p4_frame.clone(), allocator);
+    }
+}
+
+// Calls `f` with every mapped 4 KiB user page and its P1 entry, huge pages are skipped
+fn for_each_user_page<F>(mapper: &mut Mapper, mut f: F) where F: FnMut(Page, &mut Entry) {
+    let p4 = mapper.p4_mut();
+
+    for p4_index in 0..KERNEL_P4_INDEX {
+        let p3 = match p4.next_table_mut(p4_index) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        for p3_index in 0..ENTRY_COUNT {
+            let p2 = match p3.next_table_mut(p3_index) {
+                Some(p2) => p2,
+                None => continue,
+            };
+            for p2_index in 0..ENTRY_COUNT {
+                let p1 = match p2.next_table_mut(p2_index) {
+                    Some(p1) => p1,
+                    None => continue,
+                };
+                for p1_index in 0..ENTRY_COUNT {
+                    if !p1[p1_index].is_unused() {
+                        let number = (((p4_index * ENTRY_COUNT + p3_index) * ENTRY_COUNT + p2_index) * ENTRY_COUNT) + p1_index;
+                        f(Page { number: number }, &mut p1[p1_index]);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -13,6 +13,8 @@ bitflags! {
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
         const GLOBAL =          1 << 8;
+        // software defined: read only because the frame is shared, copied on the first write
+        const COPY_ON_WRITE =   1 << 9;
         const NO_EXECUTE =      1 << 63;
     }
 }
@@ -1,7 +1,7 @@
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, PageSize, ENTRY_COUNT, KERNEL_P4_INDEX};
 use super::entry::*;
-use super::table::{self, Table, Level4};
-use crate::memory::{PAGE_SIZE, Frame, FrameAllocator};
+use super::table::{Table, Level4, free_table};
+use crate::memory::{PAGE_SIZE, Frame, FrameAllocator, virt_to_phys};
 
 use core::ptr::Unique;
 
@@ -10,12 +10,16 @@ pub struct Mapper {
 }
 
 impl Mapper {
-    pub unsafe fn new() -> Mapper {
+    pub unsafe fn new(p4_frame: Frame) -> Mapper {
         return Mapper {
-            p4: Unique::new_unchecked(table::P4),
+            p4: Unique::new_unchecked(p4_frame.virtual_address() as *mut _),
         };
     }
 
+    pub fn p4_frame(&self) -> Frame {
+        return Frame::containing_address(virt_to_phys(self.p4.as_ptr() as usize));
+    }
+
     pub fn p4(&self) -> &Table<Level4> {
         return unsafe { self.p4.as_ref() };
     }
@@ -69,11 +73,32 @@ impl Mapper {
             .or_else(huge_page);
     }
 
+    // Returns the first frame and the size of the mapping which contains the page
+    pub fn translate_page_size(&self, page: Page) -> Option<(Frame, PageSize)> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return p3_entry.pointed_frame().map(|frame| (frame, PageSize::Size1GiB));
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return p2_entry.pointed_frame().map(|frame| (frame, PageSize::Size2MiB));
+        }
+
+        let p1 = p2.next_table(page.p2_index())?;
+        return p1[page.p1_index()].pointed_frame().map(|frame| (frame, PageSize::Size4KiB));
+    }
+
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
         let p4 = self.p4_mut();
-        let p3 = p4.next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        let p3 = p4.next_table_create(page.p4_index(), table_flags, allocator);
+        let p2 = p3.next_table_create(page.p3_index(), table_flags, allocator);
+        let p1 = p2.next_table_create(page.p2_index(), table_flags, allocator);
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
@@ -84,24 +109,205 @@ impl Mapper {
         return self.map_to(page, frame, flags, allocator);
     }
 
+    pub fn map_huge_to<A>(&mut self, page: Page, frame: Frame, size: PageSize, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+        if size == PageSize::Size4KiB {
+            return self.map_to(page, frame, flags, allocator);
+        }
+
+        assert!(page.number % size.page_count() == 0, "page {:?} is not aligned to {:?}", page, size);
+        assert!(frame.number % size.page_count() == 0, "frame {:?} is not aligned to {:?}", frame, size);
+
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
+        let flags = flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE;
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), table_flags, allocator);
+
+        if size == PageSize::Size1GiB {
+            assert!(crate::drivers::cpuid::has_1gib_pages(), "CPU does not support 1 GiB pages");
+            assert!(p3[page.p3_index()].is_unused());
+            p3[page.p3_index()].set(frame, flags);
+        } else {
+            let p2 = p3.next_table_create(page.p3_index(), table_flags, allocator);
+            assert!(p2[page.p2_index()].is_unused());
+            p2[page.p2_index()].set(frame, flags);
+        }
+    }
+
     pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
         let page = Page::containing_address(frame.start_address());
         return self.map_to(page, frame, flags, allocator);
     }
 
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A) where A: FrameAllocator {
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) where A: FrameAllocator {
+        let frame = self.unmap_keep_frame(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    // Like `unmap`, but the pointed frame is not owned by the mapping (eg. kernel image or a page table),
+    // so it is returned to the caller instead of the allocator. Emptied page tables are still freed.
+    pub fn unmap_keep_frame<A>(&mut self, page: Page, allocator: &mut A) -> Frame where A: FrameAllocator {
         use x86_64::instructions::tlb;
         use x86_64::VirtAddr;
 
         assert!(self.translate(page.start_address()).is_some());
 
-        let p1 = self.p4_mut()
-                    .next_table_mut(page.p4_index())
-                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                    .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()].pointed_frame().unwrap();
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        let p2 = p3.next_table_mut(page.p3_index()).expect("page is mapped by a huge page, use unmap_huge");
+        let p1 = p2.next_table_mut(page.p2_index()).expect("page is mapped by a huge page, use unmap_huge");
+
+        let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
         tlb::flush(VirtAddr::new(page.start_address() as u64));
+
+        if p1.is_empty() {
+            let p1_frame = p2[page.p2_index()].pointed_frame().unwrap();
+            p2[page.p2_index()].set_unused();
+            free_table(p1_frame, allocator);
+
+            if p2.is_empty() {
+                let p2_frame = p3[page.p3_index()].pointed_frame().unwrap();
+                p3[page.p3_index()].set_unused();
+                free_table(p2_frame, allocator);
+
+                if p3.is_empty() && page.p4_index() < KERNEL_P4_INDEX {
+                    let p3_frame = self.p4()[page.p4_index()].pointed_frame().unwrap();
+                    self.p4_mut()[page.p4_index()].set_unused();
+                    free_table(p3_frame, allocator);
+                }
+            }
+        }
+
+        return frame;
+    }
+
+    fn p1_entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        let p1 = self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))?;
+
+        let entry = &mut p1[page.p1_index()];
+        return if entry.is_unused() { None } else { Some(entry) };
+    }
+
+    // Flags of a page mapped by a 4KiB page
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        let p1 = self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))?;
+
+        let entry = &p1[page.p1_index()];
+        return if entry.is_unused() { None } else { Some(entry.flags()) };
+    }
+
+    // Changes the flags of a mapped page, the frame stays the same
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        let entry = self.p1_entry_mut(page).expect("page is not mapped");
+        let frame = entry.pointed_frame().unwrap();
+        entry.set(frame, flags | EntryFlags::PRESENT);
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+    }
+
+    // Makes the page read only and copy-on-write, the frame gets one more reference.
+    // Returns the frame and flags to map the second copy of the page with.
+    pub fn share_copy_on_write<A>(&mut self, page: Page, allocator: &mut A) -> Option<(Frame, EntryFlags)> where A: FrameAllocator {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        let entry = self.p1_entry_mut(page)?;
+        let frame = entry.pointed_frame()?;
+        let mut flags = entry.flags();
+
+        if flags.contains(EntryFlags::WRITABLE) {
+            flags = (flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE;
+            entry.set(frame.clone(), flags);
+            tlb::flush(VirtAddr::new(page.start_address() as u64));
+        }
+
+        allocator.share_frame(&frame);
+        return Some((frame, flags));
+    }
+
+    // Called on a write to a copy-on-write page, returns false if the page is not one.
+    // The last user of a frame gets it back writable, the others get a private copy.
+    pub fn resolve_copy_on_write<A>(&mut self, page: Page, allocator: &mut A) -> bool where A: FrameAllocator {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        let (frame, flags) = match self.p1_entry_mut(page) {
+            Some(entry) if entry.flags().contains(EntryFlags::COPY_ON_WRITE) => (entry.pointed_frame().unwrap(), entry.flags()),
+            _ => return false,
+        };
+        let flags = (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE;
+
+        if allocator.frame_references(&frame) == 1 {
+            self.p1_entry_mut(page).unwrap().set(frame, flags);
+        } else {
+            let copy = match allocator.allocate_frame() {
+                Some(copy) => copy,
+                None => return false,
+            };
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(frame.virtual_address() as *const u8, copy.virtual_address() as *mut u8, PAGE_SIZE);
+            }
+
+            self.p1_entry_mut(page).unwrap().set(copy, flags);
+            allocator.deallocate_frame(frame);
+        }
+
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+        return true;
+    }
+
+    pub fn unmap_huge<A>(&mut self, page: Page, size: PageSize, allocator: &mut A) where A: FrameAllocator {
+        let start_frame = self.unmap_huge_keep_frame(page, size, allocator);
+        for number in start_frame.number..start_frame.number + size.page_count() {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+    }
+
+    // Huge page counterpart of `unmap_keep_frame`, returns the first frame of the mapping
+    pub fn unmap_huge_keep_frame<A>(&mut self, page: Page, size: PageSize, allocator: &mut A) -> Frame where A: FrameAllocator {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        if size == PageSize::Size4KiB {
+            return self.unmap_keep_frame(page, allocator);
+        }
+
+        assert!(page.number % size.page_count() == 0, "page {:?} is not aligned to {:?}", page, size);
+        assert!(self.translate_page_size(page).map(|(_, mapped_size)| mapped_size) == Some(size),
+                "page {:?} is not mapped by a {:?} page", page, size);
+
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        let frame;
+
+        if size == PageSize::Size1GiB {
+            frame = p3[page.p3_index()].pointed_frame().unwrap();
+            p3[page.p3_index()].set_unused();
+            tlb::flush(VirtAddr::new(page.start_address() as u64));
+        } else {
+            let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+
+            frame = p2[page.p2_index()].pointed_frame().unwrap();
+            p2[page.p2_index()].set_unused();
+            tlb::flush(VirtAddr::new(page.start_address() as u64));
+
+            if p2.is_empty() {
+                let p2_frame = p3[page.p3_index()].pointed_frame().unwrap();
+                p3[page.p3_index()].set_unused();
+                free_table(p2_frame, allocator);
+            }
+        }
+
+        if p3.is_empty() && page.p4_index() < KERNEL_P4_INDEX {
+            let p3_frame = self.p4()[page.p4_index()].pointed_frame().unwrap();
+            self.p4_mut()[page.p4_index()].set_unused();
+            free_table(p3_frame, allocator);
+        }
+
+        return frame;
     }
 }
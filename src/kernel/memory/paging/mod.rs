@@ -1,18 +1,27 @@
 use core::ops::{Deref, DerefMut, Add};
+use core::sync::atomic::Ordering;
 use multiboot2::BootInformation;
 
 mod entry;
 mod table;
-mod temporary_page;
 mod mapper;
+mod address_space;
 
-use crate::memory::{PAGE_SIZE, Frame, FrameAllocator};
+use crate::memory::{PAGE_SIZE, KERNEL_OFFSET, PHYSICAL_MEMORY_OFFSET, Frame, FrameAllocator, BitmapFrameAllocator};
 pub use self::entry::*;
-use self::temporary_page::TemporaryPage;
+use self::table::{Table, Level4};
+pub use self::table::table_frames;
 use self::mapper::Mapper;
+pub use self::address_space::AddressSpace;
 
 const ENTRY_COUNT: usize = 512;
 
+// P4 entries from here on map the kernel half, they are shared by every address space
+const KERNEL_P4_INDEX: usize = ENTRY_COUNT / 2;
+
+// The VGA buffer and the BIOS data in the first MiB are read through the physical memory map
+const LEGACY_AREA_END: PhysicalAddress = 0x10_0000;
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -65,6 +74,27 @@ impl Add<usize> for Page {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> usize {
+        return self.page_count() * PAGE_SIZE;
+    }
+
+    pub fn page_count(self) -> usize {
+        return match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => ENTRY_COUNT,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT,
+        };
+    }
+}
+
 #[derive(Clone)]
 pub struct PageIter {
     start: Page,
@@ -87,6 +117,7 @@ impl Iterator for PageIter {
 
 pub struct ActivePageTable {
     mapper: Mapper,
+    kernel_p4: Frame,
 }
 
 impl Deref for ActivePageTable {
@@ -105,49 +136,61 @@ impl DerefMut for ActivePageTable {
 
 impl ActivePageTable {
     unsafe fn new() -> ActivePageTable {
+        use x86_64::registers::control;
+
+        let p4_frame = Frame::containing_address(control::Cr3::read().0.start_address().as_u64() as usize);
         return ActivePageTable {
-            mapper: Mapper::new(),
+            mapper: Mapper::new(p4_frame.clone()),
+            kernel_p4: p4_frame,
         };
     }
 
-    pub fn with<F>(&mut self, table: &mut InactivePageTable, temporary_page: &mut temporary_page::TemporaryPage, f: F) where F: FnOnce(&mut Mapper) {
-        use x86_64::instructions::tlb;
+    unsafe fn write_cr3(&mut self, p4_frame: Frame) {
+        use x86_64::PhysAddr;
+        use x86_64::structures::paging::PhysFrame;
         use x86_64::registers::control;
-        {
-            let backup = Frame::containing_address(control::Cr3::read().0.start_address().as_u64() as usize);
-    
-            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
-    
-            self.p4_mut()[511].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
-    
-            f(self);
-    
-            p4_table[511].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
-        }
-    
-        temporary_page.unmap(self);
+
+        control::Cr3::write(
+            PhysFrame::from_start_address_unchecked(PhysAddr::new(p4_frame.start_address() as u64)),
+            control::Cr3::read().1
+        );
+        self.mapper = Mapper::new(p4_frame);
+    }
+
+    // Page tables are reachable through the physical memory map, so inactive tables can be edited in place
+    pub fn with<F>(&mut self, table: &mut InactivePageTable, f: F) where F: FnOnce(&mut Mapper) {
+        let mut mapper = unsafe { Mapper::new(table.p4_frame.clone()) };
+        f(&mut mapper);
     }
 
+    // Replaces the kernel page table, the old one is returned
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-        use x86_64::PhysAddr;
-        use x86_64::structures::paging::PhysFrame;
-        use x86_64::registers::control;
-    
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(
-                control::Cr3::read().0.start_address().as_u64() as usize
-            ),
+            p4_frame: self.kernel_p4.clone(),
         };
         unsafe {
-            control::Cr3::write(
-                PhysFrame::from_start_address_unchecked(PhysAddr::new(new_table.p4_frame.start_address() as u64)),
-                control::Cr3::read().1
-            );
+            self.kernel_p4 = new_table.p4_frame.clone();
+            self.write_cr3(new_table.p4_frame);
         }
         return old_table;
     }
+
+    // Activates a process table, the caller keeps owning it and has to load another one before dropping it
+    pub unsafe fn load(&mut self, table: &InactivePageTable) {
+        if !self.is_loaded(table) {
+            self.write_cr3(table.p4_frame.clone());
+        }
+    }
+
+    pub fn load_kernel(&mut self) {
+        if self.mapper.p4_frame() != self.kernel_p4 {
+            unsafe { self.write_cr3(self.kernel_p4.clone()) };
+        }
+    }
+
+    pub fn is_loaded(&self, table: &InactivePageTable) -> bool {
+        return self.mapper.p4_frame() == table.p4_frame;
+    }
 }
 
 pub struct InactivePageTable {
@@ -155,32 +198,31 @@ pub struct InactivePageTable {
 }
 
 impl InactivePageTable {
-    pub fn new(frame: Frame, active_table: &mut ActivePageTable, temporary_page: &mut TemporaryPage) -> InactivePageTable {
-        {
-            let table = temporary_page.map_table_frame(frame.clone(), active_table);
-            table.zero();
-            table[511].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-        }
-        temporary_page.unmap(active_table);
+    pub fn new(frame: Frame) -> InactivePageTable {
+        let table = unsafe { &mut *(frame.virtual_address() as *mut Table<Level4>) };
+        table.zero();
+        table::table_created();
 
         return InactivePageTable { p4_frame: frame };
     }
 }
 
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable where A: FrameAllocator {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xfdcba987 }, allocator);
+pub fn remap_the_kernel(allocator: &mut BitmapFrameAllocator, boot_info: &BootInformation) -> ActivePageTable {
+    let physical_memory_end = allocator.total_frames() * PAGE_SIZE;
+    let mut mapped_end = 0;
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        InactivePageTable::new(frame)
     };
 
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+    active_table.with(&mut new_table, |mapper| {
         let elf_sections_tag = boot_info.elf_sections_tag().expect("Memory map tag required");
 
         for section in elf_sections_tag.sections() {
-            if !section.is_allocated() {
+            // the boot trampoline is not needed anymore, it would only take space in the lower half
+            if !section.is_allocated() || (section.start_address() as usize) < KERNEL_OFFSET {
                 continue;
             }
 
@@ -188,28 +230,65 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
         
             let flags = EntryFlags::from_elf_section_flags(&section);
         
-            let start_frame = Frame::containing_address(section.start_address() as usize);
-            let end_frame = Frame::containing_address(section.end_address() as usize - 1);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+            let start_page = Page::containing_address(section.start_address() as usize);
+            let end_page = Page::containing_address(section.end_address() as usize - 1);
+            for page in Page::range_inclusive(start_page, end_page) {
+                let frame = Frame::containing_address(page.start_address() - KERNEL_OFFSET);
+                mapper.map_to(page, frame, flags, allocator);
             }
         }
 
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, EntryFlags::WRITABLE, allocator);
-
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, EntryFlags::PRESENT, allocator);
+        // kernel half P3 tables are never freed, address spaces created before a kernel mapping see it too
+        for index in KERNEL_P4_INDEX..ENTRY_COUNT {
+            mapper.p4_mut().next_table_create(index, EntryFlags::empty(), allocator);
         }
+
+        // map all physical memory at PHYSICAL_MEMORY_OFFSET, using the biggest pages the CPU supports
+        let size = if crate::drivers::cpuid::has_1gib_pages() { PageSize::Size1GiB } else { PageSize::Size2MiB };
+        let end = (physical_memory_end + size.bytes() - 1) & !(size.bytes() - 1);
+        map_physical_memory(mapper, boot_info, 0, end, size, allocator);
+        mapped_end = end;
     });
 
     let old_table = active_table.switch(new_table);
+    crate::memory::PHYSICAL_MEMORY_MAPPED.store(mapped_end, Ordering::Relaxed);
+    allocator.set_mapped_end(mapped_end);
 
-    // turn the old p4 page into a guard page
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    // turn the old p4 page into a guard page, it lies right below the boot stack
+    let old_p4_page = Page::containing_address(KERNEL_OFFSET + old_table.p4_frame.start_address());
+    active_table.unmap_keep_frame(old_p4_page, allocator);
+    crate::memory::fault::register_guard("boot stack", old_p4_page.start_address(), old_p4_page.start_address() + PAGE_SIZE);
 
     return active_table;
 }
+
+// Maps the RAM between `start` and `end` with pages of `size`. Pages which are only partly RAM are split
+// into smaller ones, so device memory in the holes (the local APIC, the I/O APIC) never gets a cached alias.
+fn map_physical_memory(mapper: &mut Mapper, boot_info: &BootInformation, start: PhysicalAddress, end: PhysicalAddress, size: PageSize, allocator: &mut BitmapFrameAllocator) {
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+
+    let mut address = start;
+    while address < end {
+        let page_end = address + size.bytes();
+
+        let mut covered = if address < LEGACY_AREA_END { core::cmp::min(page_end, LEGACY_AREA_END) - address } else { 0 };
+        for area in memory_map_tag.memory_areas() {
+            let area_start = core::cmp::max(area.start_address() as usize, core::cmp::max(address, LEGACY_AREA_END));
+            let area_end = core::cmp::min(area.end_address() as usize, page_end);
+            if area_start < area_end {
+                covered += area_end - area_start;
+            }
+        }
+
+        if covered == size.bytes() || (covered > 0 && size == PageSize::Size4KiB) {
+            let page = Page::containing_address(PHYSICAL_MEMORY_OFFSET + address);
+            let frame = Frame::containing_address(address);
+            mapper.map_huge_to(page, frame, size, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator);
+        } else if covered > 0 {
+            let smaller = if size == PageSize::Size1GiB { PageSize::Size2MiB } else { PageSize::Size4KiB };
+            map_physical_memory(mapper, boot_info, address, page_end, smaller, allocator);
+        }
+
+        address = page_end;
+    }
+}
@@ -1,11 +1,26 @@
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::memory::FrameAllocator;
+use crate::memory::{Frame, FrameAllocator};
 use crate::memory::paging::entry::*;
 use crate::memory::paging::ENTRY_COUNT;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+// Frames used by page tables (except for the boot ones in the kernel image), for the memory statistics
+static TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn table_frames() -> usize {
+    return TABLE_FRAMES.load(Ordering::Relaxed);
+}
+
+pub fn table_created() {
+    TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn free_table<A>(frame: Frame, allocator: &mut A) where A: FrameAllocator {
+    TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    allocator.deallocate_frame(frame);
+}
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -18,17 +33,18 @@ impl<L> Table<L> where L: TableLevel {
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.iter().all(|entry| entry.is_unused());
+    }
 }
 
 impl<L> Table<L> where L: HierarchicalLevel {
     fn next_table_address(&self, index: usize) -> Option<usize> {
-        let entry_flags = self[index].flags();
-        if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE) {
-            let table_address = self as *const _ as usize;
-            return Some((table_address << 9) | (index << 12));
-        } else {
+        if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
             return None;
         }
+        return self[index].pointed_frame().map(|frame| frame.virtual_address());
     }
 
     pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
@@ -39,13 +55,19 @@ impl<L> Table<L> where L: HierarchicalLevel {
         return self.next_table_address(index).map(|address| unsafe { &mut *(address as *mut _) });
     }
 
-    pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel> where A: FrameAllocator {
+    // `flags` are added to the entry even if the table exists already (user pages need USER_ACCESSIBLE on every level)
+    pub fn next_table_create<A>(&mut self, index: usize, flags: EntryFlags, allocator: &mut A) -> &mut Table<L::NextLevel> where A: FrameAllocator {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
-                    "mapping code does not support huge pages");
+                    "cannot create a page table inside a huge page mapping");
             let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            table_created();
+            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | flags);
             self.next_table_mut(index).unwrap().zero();
+        } else if !self.entries[index].flags().contains(flags) {
+            let frame = self.entries[index].pointed_frame().unwrap();
+            let table_flags = self.entries[index].flags() | flags;
+            self.entries[index].set(frame, table_flags);
         }
         return self.next_table_mut(index).unwrap();
     }
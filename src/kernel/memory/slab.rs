@@ -0,0 +1,309 @@
+// Slab caches. The kmalloc size classes in `allocator` are plain caches, kernel types which are
+// allocated all the time get their own named `ObjectCache`. So far that is only `AddressSpace`, there are
+// no tasks or inodes yet and page tables are whole frames from the frame allocator.
+// Lock order: a cache is never locked while its pages are mapped or unmapped, so the cache locks
+// come after everything else, see `allocator::alloc_pages`.
+use core::marker::PhantomData;
+use core::mem::{size_of, align_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::memory::PAGE_SIZE;
+use crate::memory::allocator;
+use crate::sync::SpinLock;
+
+// Slabs are grown until at least this many objects fit in one
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+// Every slab starts with this header, slabs are aligned to their size so the header
+// of any object can be found by masking the object address.
+#[repr(C)]
+struct Slab {
+    cache: *const SlabCache,
+    free_list: *mut FreeObject,
+    in_use: usize,
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct CacheInner {
+    // slabs with at least one free object, full slabs are not linked anywhere
+    partial: *mut Slab,
+    slabs: usize,
+    empty_slabs: usize,
+    objects_in_use: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+unsafe impl Send for CacheInner {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    pub objects_per_slab: usize,
+    pub objects_in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    stride: usize,
+    first_object: usize,
+    slab_size: usize,
+    inner: SpinLock<CacheInner>,
+    registered: AtomicBool,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    return (value + align - 1) & !(align - 1);
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    return if a > b { a } else { b };
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, object_size: usize, object_align: usize) -> SlabCache {
+        let align = max(object_align, align_of::<FreeObject>());
+        let stride = align_up(max(object_size, size_of::<FreeObject>()), align);
+        let first_object = align_up(size_of::<Slab>(), align);
+
+        let mut slab_size = PAGE_SIZE;
+        while slab_size < first_object + stride * MIN_OBJECTS_PER_SLAB {
+            slab_size *= 2;
+        }
+
+        return SlabCache {
+            name: name,
+            object_size: object_size,
+            stride: stride,
+            first_object: first_object,
+            slab_size: slab_size,
+            inner: SpinLock::new(name, CacheInner {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                empty_slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            registered: AtomicBool::new(false),
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        return (self.slab_size - self.first_object) / self.stride;
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            allocator::register_cache(self);
+        }
+
+        let mut inner = self.inner.lock();
+
+        if inner.partial.is_null() {
+            // mapping the pages takes the memory controller, the cache is unlocked meanwhile
+            drop(inner);
+            let base = allocator::alloc_pages(self.slab_size, self.slab_size)?;
+            let slab = unsafe { self.init_slab(base) };
+
+            inner = self.inner.lock();
+            unsafe {
+                (*slab).next = inner.partial;
+                if !inner.partial.is_null() {
+                    (*inner.partial).prev = slab;
+                }
+            }
+            inner.partial = slab;
+            inner.slabs += 1;
+            inner.empty_slabs += 1;
+        }
+
+        unsafe {
+            let slab = &mut *inner.partial;
+            let object = slab.free_list;
+
+            slab.free_list = (*object).next;
+            if slab.in_use == 0 {
+                inner.empty_slabs -= 1;
+            }
+            slab.in_use += 1;
+
+            if slab.free_list.is_null() {
+                // the slab is full now
+                inner.partial = slab.next;
+                if !slab.next.is_null() {
+                    (*slab.next).prev = ptr::null_mut();
+                }
+                slab.next = ptr::null_mut();
+            }
+
+            inner.objects_in_use += 1;
+            inner.allocations += 1;
+
+            return NonNull::new(object as *mut u8);
+        }
+    }
+
+    pub unsafe fn free(&'static self, object: NonNull<u8>) {
+        let mut inner = self.inner.lock();
+
+        let slab_address = object.as_ptr() as usize & !(self.slab_size - 1);
+        let slab = &mut *(slab_address as *mut Slab);
+        assert!(slab.cache == self as *const _, "object {:p} freed to wrong cache {}", object, self.name);
+
+        let was_full = slab.free_list.is_null();
+
+        let object = object.as_ptr() as *mut FreeObject;
+        (*object).next = slab.free_list;
+        slab.free_list = object;
+        slab.in_use -= 1;
+
+        inner.objects_in_use -= 1;
+        inner.frees += 1;
+
+        if was_full {
+            slab.prev = ptr::null_mut();
+            slab.next = inner.partial;
+            if !inner.partial.is_null() {
+                (*inner.partial).prev = slab;
+            }
+            inner.partial = slab;
+        }
+
+        if slab.in_use == 0 {
+            inner.empty_slabs += 1;
+
+            // keep a single empty slab around, so alloc/free pairs do not map and unmap pages all the time
+            if inner.empty_slabs > 1 {
+                if slab.prev.is_null() {
+                    inner.partial = slab.next;
+                } else {
+                    (*slab.prev).next = slab.next;
+                }
+                if !slab.next.is_null() {
+                    (*slab.next).prev = slab.prev;
+                }
+
+                inner.slabs -= 1;
+                inner.empty_slabs -= 1;
+
+                drop(inner);
+                allocator::free_pages(slab_address, self.slab_size);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+
+        return CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_size: self.slab_size,
+            slabs: inner.slabs,
+            objects_per_slab: self.objects_per_slab(),
+            objects_in_use: inner.objects_in_use,
+            allocations: inner.allocations,
+            frees: inner.frees,
+        };
+    }
+
+    unsafe fn init_slab(&'static self, base: usize) -> *mut Slab {
+        let mut free_list: *mut FreeObject = ptr::null_mut();
+
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = (base + self.first_object + index * self.stride) as *mut FreeObject;
+            (*object).next = free_list;
+            free_list = object;
+        }
+
+        let slab = base as *mut Slab;
+        slab.write(Slab {
+            cache: self,
+            free_list: free_list,
+            in_use: 0,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+        return slab;
+    }
+}
+
+// A named cache for objects of type `T`
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    // only the layout of `T` matters, the cache itself holds no `T`
+    _type: PhantomData<fn() -> T>,
+}
+
+// Owns an object allocated from an `ObjectCache`, like a `Box`
+pub struct CacheBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for CacheBox<T> {}
+unsafe impl<T: Sync> Sync for CacheBox<T> {}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> ObjectCache<T> {
+        return ObjectCache {
+            cache: SlabCache::new(name, size_of::<T>(), align_of::<T>()),
+            _type: PhantomData,
+        };
+    }
+
+    pub fn alloc(&'static self, value: T) -> Option<CacheBox<T>> {
+        let object = self.cache.alloc()?.cast::<T>();
+        unsafe { object.as_ptr().write(value) };
+
+        return Some(CacheBox {
+            object: object,
+            cache: self,
+        });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        return self.cache.stats();
+    }
+}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        return unsafe { self.object.as_ref() };
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        return unsafe { self.object.as_mut() };
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.cache.free(self.object.cast());
+        }
+    }
+}
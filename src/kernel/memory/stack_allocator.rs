@@ -1,43 +1,118 @@
-use crate::memory::paging::{self, Page, PageIter, ActivePageTable};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::paging::{Page, EntryFlags, ActivePageTable, VirtualAddress};
 use crate::memory::{PAGE_SIZE, FrameAllocator};
 
+pub const MAX_STACK_SLOTS: usize = 4096;
+
+// The stack area is split into equal slots, every slot starts with a guard page
+// followed by room for the biggest stack. Stacks are placed at the top of their slot,
+// so pages between the guard page and the stack bottom are left unmapped too. That whole
+// unmapped part is the guard of the stack, see `is_guard`.
+// Bounds of the stack area, read by `is_guard` without the memory controller (which may be locked
+// by the code whose stack overflowed)
+static AREA_START: AtomicUsize = AtomicUsize::new(0);
+static AREA_END: AtomicUsize = AtomicUsize::new(0);
+
+// True if `address` is in the stack area and its page is not mapped. Stacks fill their slots from
+// the top, so such an address is in the guard part of a slot (a free slot is a guard as a whole).
+// The caller has to know the page is not present, a page fault without PROTECTION_VIOLATION.
+pub fn is_guard(address: VirtualAddress) -> bool {
+    return address >= AREA_START.load(Ordering::Relaxed) && address < AREA_END.load(Ordering::Relaxed);
+}
+
 pub struct StackAllocator {
-    range: PageIter,
+    area_start: Page,
+    slot_pages: usize,
+    slot_count: usize,
+    used_slots: [u64; MAX_STACK_SLOTS / 64],
+    mapped_pages: usize,
 }
 
 impl StackAllocator {
-    pub fn new(page_range: PageIter) -> StackAllocator {
-        return StackAllocator { range: page_range };
+    pub fn new(area_start: Page, slot_count: usize, max_stack_pages: usize) -> StackAllocator {
+        assert!(slot_count <= MAX_STACK_SLOTS, "at most {} kernel stacks are supported", MAX_STACK_SLOTS);
+        assert!(max_stack_pages > 0);
+
+        let area_start_address = area_start.start_address();
+        AREA_START.store(area_start_address, Ordering::Relaxed);
+        AREA_END.store(area_start_address + StackAllocator::area_size(slot_count, max_stack_pages), Ordering::Relaxed);
+
+        return StackAllocator {
+            area_start: area_start,
+            slot_pages: max_stack_pages + 1,
+            slot_count: slot_count,
+            used_slots: [0; MAX_STACK_SLOTS / 64],
+            mapped_pages: 0,
+        };
+    }
+
+    pub fn area_size(slot_count: usize, max_stack_pages: usize) -> usize {
+        return slot_count * (max_stack_pages + 1) * PAGE_SIZE;
     }
 
     pub fn alloc_stack<FA: FrameAllocator>(&mut self, active_table: &mut ActivePageTable, frame_allocator: &mut FA, size_in_pages: usize) -> Option<Stack> {
-        if size_in_pages == 0 {
+        if size_in_pages == 0 || size_in_pages >= self.slot_pages {
             return None;
         }
 
-        let mut range = self.range.clone();
+        let slot = (0..self.slot_count).find(|&slot| !self.is_used(slot))?;
 
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            range.nth(size_in_pages - 2)
-        };
+        let slot_end = self.area_start + ((slot + 1) * self.slot_pages - 1);
+        let start = self.area_start + ((slot + 1) * self.slot_pages - size_in_pages);
 
-        return match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                self.range = range;
-
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::EntryFlags::WRITABLE, frame_allocator);
+        for (index, page) in Page::range_inclusive(start, slot_end).enumerate() {
+            match frame_allocator.allocate_frame() {
+                Some(frame) => active_table.map_to(page, frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, frame_allocator),
+                None => {
+                    for mapped_page in Page::range_inclusive(start, slot_end).take(index) {
+                        active_table.unmap(mapped_page, frame_allocator);
+                    }
+                    return None;
                 }
-
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
             }
-            _ => None,
-        };
+        }
+
+        self.set_used(slot, true);
+        self.mapped_pages += size_in_pages;
+
+        let top_of_stack = slot_end.start_address() + PAGE_SIZE;
+        return Some(Stack::new(top_of_stack, start.start_address()));
+    }
+
+    pub fn free_stack<FA: FrameAllocator>(&mut self, active_table: &mut ActivePageTable, frame_allocator: &mut FA, stack: Stack) {
+        let start = Page::containing_address(stack.bottom());
+        let end = Page::containing_address(stack.top() - 1);
+
+        let slot = (start.start_address() - self.area_start.start_address()) / (self.slot_pages * PAGE_SIZE);
+        assert!(slot < self.slot_count && self.is_used(slot), "stack {:?} was not allocated by this allocator", stack);
+
+        for page in Page::range_inclusive(start, end) {
+            active_table.unmap(page, frame_allocator);
+            self.mapped_pages -= 1;
+        }
+
+        self.set_used(slot, false);
+    }
+
+    pub fn used_stacks(&self) -> usize {
+        return self.used_slots.iter().map(|word| word.count_ones() as usize).sum();
+    }
+
+    pub fn mapped_pages(&self) -> usize {
+        return self.mapped_pages;
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        return self.used_slots[slot / 64] & (1 << (slot % 64)) != 0;
+    }
+
+    fn set_used(&mut self, slot: usize, used: bool) {
+        if used {
+            self.used_slots[slot / 64] |= 1 << (slot % 64);
+        } else {
+            self.used_slots[slot / 64] &= !(1 << (slot % 64));
+        }
     }
 }
 
@@ -64,4 +139,3 @@ impl Stack {
         return self.bottom;
     }
 }
-
@@ -0,0 +1,339 @@
+// Virtual memory areas of a user address space, the bookkeeping behind mmap, munmap, mprotect and brk
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::{self, PAGE_SIZE, Frame, FrameAllocator};
+use crate::memory::fault::PageSource;
+use crate::memory::paging::{EntryFlags, VirtualAddress};
+use crate::sync::{SpinLock, SpinLockGuard};
+
+// Lowest address mmap hands out (vm.mmap_min_addr on Linux)
+pub const MMAP_MIN_ADDRESS: VirtualAddress = 0x1_0000;
+// Mappings without a usable hint are placed top-down from here
+pub const MMAP_TOP: VirtualAddress = 0x0000_7fff_0000_0000;
+// End of the user half
+pub const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+bitflags! {
+    // PROT_* values of the Linux ABI
+    pub struct Protection: u32 {
+        const READ =  0x1;
+        const WRITE = 0x2;
+        const EXEC =  0x4;
+    }
+}
+
+bitflags! {
+    // MAP_* values of the Linux ABI, only the ones the kernel understands
+    pub struct MapFlags: u32 {
+        const SHARED =          0x01;
+        const PRIVATE =         0x02;
+        const FIXED =           0x10;
+        const ANONYMOUS =       0x20;
+        const FIXED_NOREPLACE = 0x10_0000;
+    }
+}
+
+impl Protection {
+    // x86 cannot map a page write only, so WRITE implies READ. PROT_NONE pages stay mapped
+    // for the kernel, but lose USER_ACCESSIBLE, so their contents survive another mprotect.
+    pub fn entry_flags(self) -> EntryFlags {
+        if self.is_empty() {
+            return EntryFlags::NO_EXECUTE;
+        }
+
+        let mut flags = EntryFlags::USER_ACCESSIBLE;
+        if self.contains(Protection::WRITE) {
+            flags = flags | EntryFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXEC) {
+            flags = flags | EntryFlags::NO_EXECUTE;
+        }
+        return flags;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    InvalidArgument,
+    OutOfMemory,
+    AlreadyExists,
+}
+
+impl VmError {
+    // Negated errno, as returned by the syscalls
+    pub fn errno(self) -> isize {
+        return match self {
+            VmError::InvalidArgument => -22, // EINVAL
+            VmError::OutOfMemory => -12,     // ENOMEM
+            VmError::AlreadyExists => -17,   // EEXIST
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Backing {
+    Anonymous,
+    // there is no page cache yet, so every mapping of a file gets its own copy of the pages
+    File { source: &'static dyn PageSource, offset: usize },
+}
+
+impl Backing {
+    fn advance(self, bytes: usize) -> Backing {
+        return match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { source, offset } => Backing::File { source: source, offset: offset + bytes },
+        };
+    }
+
+    // True if a mapping backed by `next` can continue a mapping of `size` bytes backed by `self`
+    fn continues_with(self, size: usize, next: Backing) -> bool {
+        return match (self, next) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (Backing::File { source, offset }, Backing::File { source: next_source, offset: next_offset }) =>
+                core::ptr::eq(source as *const dyn PageSource as *const u8, next_source as *const dyn PageSource as *const u8)
+                    && offset + size == next_offset,
+            _ => false,
+        };
+    }
+}
+
+// Frames of every MAP_SHARED mapping, by object id and page index. Each frame in here holds a
+// reference of its own. One lock for all objects, objects come and go with the mappings and a lock
+// of their own would need a new lock class every time. Changing the map allocates, so this lock is
+// taken before the memory controller and is never taken with it held.
+static SHARED_FRAMES: SpinLock<BTreeMap<(usize, usize), Frame>> = SpinLock::new("shared frames", BTreeMap::new());
+static NEXT_SHARED_OBJECT: AtomicUsize = AtomicUsize::new(0);
+
+// Backing object of a MAP_SHARED mapping. VMAs split from the mapping and their copies in forked
+// address spaces all point to the same object, so a page populated through any of them is found
+// by the others.
+struct SharedObject {
+    id: usize,
+}
+
+impl Drop for SharedObject {
+    // The last VMA of the object must be dropped with the memory controller unlocked
+    fn drop(&mut self) {
+        let mut removed = Vec::new();
+        SHARED_FRAMES.lock().retain(|&(object, _), frame| {
+            if object == self.id {
+                removed.push(frame.clone());
+            }
+            return object != self.id;
+        });
+
+        // unlocked before `removed` is freed
+        let mut controller = memory::controller();
+        for frame in removed.drain(..) {
+            controller.frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+// The part of a shared object a VMA maps, starting `offset` bytes into the object
+#[derive(Clone)]
+pub struct SharedPages {
+    object: Arc<SharedObject>,
+    offset: usize,
+}
+
+impl SharedPages {
+    pub fn new() -> SharedPages {
+        return SharedPages {
+            object: Arc::new(SharedObject { id: NEXT_SHARED_OBJECT.fetch_add(1, Ordering::Relaxed) }),
+            offset: 0,
+        };
+    }
+
+    // Key in `SHARED_FRAMES` of the page `bytes` into the VMA
+    pub fn key(&self, bytes: usize) -> (usize, usize) {
+        return (self.object.id, (self.offset + bytes) / PAGE_SIZE);
+    }
+
+    fn advance(&self, bytes: usize) -> SharedPages {
+        return SharedPages { object: self.object.clone(), offset: self.offset + bytes };
+    }
+
+    fn continues_with(&self, size: usize, next: &SharedPages) -> bool {
+        return Arc::ptr_eq(&self.object, &next.object) && self.offset + size == next.offset;
+    }
+}
+
+// The frames of all shared objects, see `SHARED_FRAMES`. Only tried, like the memory controller
+// in the page fault handler.
+pub fn try_shared_frames() -> Option<SpinLockGuard<'static, BTreeMap<(usize, usize), Frame>>> {
+    return SHARED_FRAMES.try_lock();
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub protection: Protection,
+    // the pages of MAP_SHARED mappings, private mappings have none
+    pub shared: Option<SharedPages>,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        return address >= self.start && address < self.end;
+    }
+
+    pub fn entry_flags(&self) -> EntryFlags {
+        return self.protection.entry_flags();
+    }
+
+    // Backing of the page containing `address`
+    pub fn backing_at(&self, address: VirtualAddress) -> Backing {
+        return self.backing.advance((address & !(PAGE_SIZE - 1)) - self.start);
+    }
+
+    pub fn is_shared(&self) -> bool {
+        return self.shared.is_some();
+    }
+
+    fn can_merge(&self, next: &Vma) -> bool {
+        let size = self.end - self.start;
+        let shared = match (&self.shared, &next.shared) {
+            (None, None) => true,
+            (Some(pages), Some(next_pages)) => pages.continues_with(size, next_pages),
+            _ => false,
+        };
+        return self.end == next.start && self.protection == next.protection && shared
+            && self.backing.continues_with(size, next.backing);
+    }
+}
+
+// VMAs ordered by their start address, they never overlap
+pub struct VmaTree {
+    areas: BTreeMap<VirtualAddress, Vma>,
+}
+
+impl VmaTree {
+    pub fn new() -> VmaTree {
+        return VmaTree { areas: BTreeMap::new() };
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        return self.areas.values();
+    }
+
+    pub fn find(&self, address: VirtualAddress) -> Option<&Vma> {
+        return self.areas.range(..=address).next_back().map(|(_, vma)| vma).filter(|vma| vma.contains(address));
+    }
+
+    pub fn is_free(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        if let Some((_, vma)) = self.areas.range(..end).next_back() {
+            return vma.end <= start;
+        }
+        return true;
+    }
+
+    // Highest free range of `length` bytes below MMAP_TOP
+    pub fn find_free(&self, length: usize) -> Option<VirtualAddress> {
+        let mut end = MMAP_TOP;
+        for vma in self.areas.range(..MMAP_TOP).rev().map(|(_, vma)| vma) {
+            if vma.end < end && end - vma.end >= length {
+                return Some(end - length);
+            }
+            end = core::cmp::min(end, vma.start);
+        }
+
+        if end >= MMAP_MIN_ADDRESS + length {
+            return Some(end - length);
+        }
+        return None;
+    }
+
+    // The range has to be free
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(self.is_free(vma.start, vma.end), "VMA {:#x}-{:#x} overlaps another one", vma.start, vma.end);
+        let (start, end) = (vma.start, vma.end);
+        self.areas.insert(start, vma);
+        self.merge_around(start, end);
+    }
+
+    // Removes the part of every VMA inside of the range, returns the removed parts
+    pub fn remove_range(&mut self, start: VirtualAddress, end: VirtualAddress) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+
+        let starts: Vec<VirtualAddress> = self.areas.range(start..end).map(|(&start, _)| start).collect();
+        return starts.into_iter().map(|start| self.areas.remove(&start).unwrap()).collect();
+    }
+
+    // Changes the protection of the range, which has to be fully covered by VMAs
+    pub fn protect_range(&mut self, start: VirtualAddress, end: VirtualAddress, protection: Protection) -> Result<(), VmError> {
+        if !self.is_covered(start, end) {
+            return Err(VmError::OutOfMemory);
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.areas.range_mut(start..end) {
+            vma.protection = protection;
+        }
+        self.merge_around(start, end);
+        return Ok(());
+    }
+
+    fn is_covered(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        let mut address = start;
+        while address < end {
+            match self.find(address) {
+                Some(vma) => address = vma.end,
+                None => return false,
+            }
+        }
+        return true;
+    }
+
+    // Splits the VMA containing the address in two, so that one of them starts at the address
+    fn split_at(&mut self, address: VirtualAddress) {
+        let vma = match self.find(address) {
+            Some(vma) if vma.start != address => vma.clone(),
+            _ => return,
+        };
+
+        self.areas.get_mut(&vma.start).unwrap().end = address;
+        self.areas.insert(address, Vma {
+            start: address,
+            shared: vma.shared.as_ref().map(|pages| pages.advance(address - vma.start)),
+            backing: vma.backing.advance(address - vma.start),
+            ..vma
+        });
+    }
+
+    // Merges compatible neighbours in the range and at both of its ends
+    fn merge_around(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        let first = self.areas.range(..start).next_back().map_or(start, |(&start, _)| start);
+
+        let mut current = first;
+        while current <= end {
+            let vma = match self.areas.get(&current) {
+                Some(vma) => vma.clone(),
+                None => match self.areas.range(current..).next() {
+                    Some((&next_start, _)) => { current = next_start; continue; }
+                    None => return,
+                },
+            };
+
+            if self.areas.get(&vma.end).map_or(false, |next| vma.can_merge(next)) {
+                let next = self.areas.remove(&vma.end).unwrap();
+                self.areas.get_mut(&vma.start).unwrap().end = next.end;
+            } else {
+                current = vma.end;
+            }
+        }
+    }
+}
+
+impl Clone for VmaTree {
+    fn clone(&self) -> VmaTree {
+        return VmaTree { areas: self.areas.clone() };
+    }
+}
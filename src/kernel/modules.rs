@@ -0,0 +1,71 @@
+// Modules loaded by the bootloader (`module2 /boot/initrd.img initrd` in grub.cfg), eg. an initramfs.
+// Their frames are reserved by `memory::init`, here they get mapped read only.
+use multiboot2::BootInformation;
+use spin::Once;
+
+use crate::memory::{self, EntryFlags, PhysicalAddress, VirtualAddress};
+
+pub const MAX_MODULES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    // first word of the module command line
+    pub name: &'static str,
+    pub command_line: &'static str,
+    pub physical_start: PhysicalAddress,
+    start: VirtualAddress,
+    size: usize,
+}
+
+impl Module {
+    pub fn data(&self) -> &'static [u8] {
+        if self.size == 0 {
+            return &[];
+        }
+        return unsafe { core::slice::from_raw_parts(self.start as *const u8, self.size) };
+    }
+
+    pub fn size(&self) -> usize {
+        return self.size;
+    }
+}
+
+static MODULES: Once<[Option<Module>; MAX_MODULES]> = Once::new();
+
+pub fn init(boot_info: &BootInformation) {
+    assert_has_not_been_called!("modules::init can be called only once");
+
+    // `memory::init` made sure that there are no more than MAX_MODULES
+    let mut modules = [None; MAX_MODULES];
+
+    for (slot, tag) in modules.iter_mut().zip(boot_info.module_tags()) {
+        let physical_start = tag.start_address() as usize;
+        let size = (tag.end_address() - tag.start_address()) as usize;
+
+        let start = if size == 0 {
+            0
+        } else {
+            memory::controller().map_physical(physical_start, size, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE)
+                .expect("no virtual space left for boot modules")
+        };
+
+        let command_line = tag.name().trim();
+        *slot = Some(Module {
+            name: command_line.split_whitespace().next().unwrap_or(""),
+            command_line: command_line,
+            physical_start: physical_start,
+            start: start,
+            size: size,
+        });
+    }
+
+    MODULES.call_once(|| modules);
+}
+
+pub fn get(name: &str) -> Option<&'static Module> {
+    return iter().find(|module| module.name == name);
+}
+
+pub fn iter() -> impl Iterator<Item = &'static Module> {
+    return MODULES.get().into_iter().flat_map(|modules| modules.iter().flatten());
+}
@@ -0,0 +1,134 @@
+// Shutting down and restarting the machine. Every method is tried in turn, from the ACPI ones
+// to the legacy ones, and the last resort is a triple fault (reboot) or halting (power off).
+use x86_64::instructions::port::Port;
+
+use crate::acpi::{self, Fadt};
+
+// PM1 control register
+const SCI_ENABLE: u64 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u64 = 10;
+const SLEEP_TYPE_MASK: u64 = 0b111 << SLEEP_TYPE_SHIFT;
+const SLEEP_ENABLE: u64 = 1 << 13;
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const INPUT_BUFFER_FULL: u8 = 1 << 1;
+const PULSE_RESET_LINE: u8 = 0xfe;
+
+fn halt_forever() -> ! {
+    x86_64::instructions::interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Firmware in legacy mode owns the PM registers until it is asked to hand them over
+fn enable_acpi_mode(fadt: &Fadt) {
+    let pm1a_control = match fadt.pm1a_control_block {
+        Some(block) => block,
+        None => return,
+    };
+    if pm1a_control.read().map_or(true, |value| value & SCI_ENABLE != 0) {
+        return;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+
+    // the switch can take a while, but give up after about 3 seconds
+    for _ in 0..300 {
+        if pm1a_control.read().map_or(false, |value| value & SCI_ENABLE != 0) {
+            return;
+        }
+        crate::timer::busy_wait(10);
+    }
+}
+
+// ACPI S5 (soft-off) through the PM1 control blocks
+fn acpi_power_off() {
+    let (fadt, (sleep_type_a, sleep_type_b)) = match (acpi::fadt(), acpi::s5_sleep_types()) {
+        (Some(fadt), Some(sleep_types)) => (fadt, sleep_types),
+        _ => return,
+    };
+
+    enable_acpi_mode(fadt);
+
+    let blocks = [(fadt.pm1a_control_block, sleep_type_a), (fadt.pm1b_control_block, sleep_type_b)];
+    // the sleep type is written first and the sleep enable bit separately, like ACPICA does
+    for &enable in &[0, SLEEP_ENABLE] {
+        for &(block, sleep_type) in blocks.iter() {
+            if let Some(block) = block {
+                let value = block.read().unwrap_or(0) & !(SLEEP_TYPE_MASK | SLEEP_ENABLE);
+                block.write(value | ((sleep_type as u64) << SLEEP_TYPE_SHIFT) & SLEEP_TYPE_MASK | enable);
+            }
+        }
+    }
+
+    crate::timer::busy_wait(100);
+}
+
+pub fn power_off() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    acpi_power_off();
+
+    crate::println_all!("\x1b[1;33mCould not power off, it is now safe to turn off the computer");
+    halt_forever();
+}
+
+fn acpi_reset() {
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(register) = fadt.reset_register {
+            register.write(fadt.reset_value as u64);
+            crate::timer::busy_wait(100);
+        }
+    }
+}
+
+// The 8042 keyboard controller can pulse the CPU reset line
+fn keyboard_controller_reset() {
+    if let Some(fadt) = acpi::fadt() {
+        if fadt.revision >= 3 && fadt.boot_architecture & Fadt::HAS_8042 == 0 {
+            return;
+        }
+    }
+
+    let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+    let mut command = Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND);
+    unsafe {
+        for _ in 0..1000 {
+            if status.read() & INPUT_BUFFER_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        command.write(PULSE_RESET_LINE);
+    }
+    crate::timer::busy_wait(100);
+}
+
+// An exception with an empty IDT cannot be delivered, which ends in a triple fault
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{DescriptorTablePointer, lidt};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        base: VirtAddr::new(0),
+        limit: 0,
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    halt_forever();
+}
+
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+}
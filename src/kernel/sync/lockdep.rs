@@ -0,0 +1,195 @@
+// Lock validator (the `lock-debug` feature). Every lock gets a class the first time it is taken,
+// and the validator remembers which classes were taken while others were held, and in which context.
+// It panics on the first acquisition which can deadlock:
+// - a lock taken while holding one that was taken after it before, directly or through other locks
+// - a lock taken in an interrupt handler which is also held with interrupts enabled elsewhere
+// - a lock taken in a tasklet which is also held in thread context with interrupts enabled
+// The report is made before spinning, so it shows up even if that acquisition would deadlock.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::interrupts::{deferred, irq};
+
+// the dependencies of a class are a bitmap in a u64
+const MAX_CLASSES: usize = 64;
+const MAX_HELD: usize = 16;
+
+// usage bits of a class
+const IN_IRQ: u8 = 1 << 0;
+const IN_TASKLET: u8 = 1 << 1;
+const INTERRUPTS_ENABLED: u8 = 1 << 2;
+// held in thread context with interrupts enabled, where tasklets can run on top of it
+const THREAD_INTERRUPTS_ENABLED: u8 = 1 << 3;
+
+// Embedded in every lock, 0 until the lock is first taken, then its class number + 1
+pub struct Class {
+    id: AtomicUsize,
+}
+
+impl Class {
+    pub const fn new() -> Class {
+        return Class { id: AtomicUsize::new(0) };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ClassInfo {
+    name: &'static str,
+    usage: u8,
+}
+
+enum Violation {
+    Recursive(&'static str),
+    Inversion { held: &'static str, taken: &'static str },
+    InterruptUnsafe { name: &'static str, context: &'static str },
+}
+
+struct State {
+    classes: [Option<ClassInfo>; MAX_CLASSES],
+    // bit b of after[a] is set once class b was taken while a was held
+    after: [u64; MAX_CLASSES],
+    // the locks held by every context on the stack, interrupt handlers on top of what they interrupted
+    held: [usize; MAX_HELD],
+    depth: usize,
+}
+
+// Only locked with interrupts disabled, locks are taken at interrupt time too
+static STATE: Mutex<State> = Mutex::new(State {
+    classes: [None; MAX_CLASSES],
+    after: [0; MAX_CLASSES],
+    held: [0; MAX_HELD],
+    depth: 0,
+});
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+impl State {
+    fn class(&mut self, class: &Class, name: &'static str) -> usize {
+        let id = class.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id - 1;
+        }
+
+        let id = match self.classes.iter().position(|info| info.is_none()) {
+            Some(id) => id,
+            None => panic!("lockdep: more than {} lock classes", MAX_CLASSES),
+        };
+        self.classes[id] = Some(ClassInfo { name: name, usage: 0 });
+        class.id.store(id + 1, Ordering::Relaxed);
+        return id;
+    }
+
+    fn name(&self, id: usize) -> &'static str {
+        return self.classes[id].unwrap().name;
+    }
+
+    // Whether `to` was taken while `from` was held, directly or with other locks in between
+    fn depends(&self, from: usize, to: usize) -> bool {
+        let mut seen = 0u64;
+        let mut reached = self.after[from];
+        while reached & !seen != 0 {
+            let next = (reached & !seen).trailing_zeros() as usize;
+            seen |= 1 << next;
+            reached |= self.after[next];
+        }
+        return reached & (1 << to) != 0;
+    }
+
+    fn acquire(&mut self, id: usize, usage: u8, try_lock: bool) -> Option<Violation> {
+        let name = self.name(id);
+
+        let info = self.classes[id].as_mut().unwrap();
+        info.usage |= usage;
+        if info.usage & (IN_IRQ | INTERRUPTS_ENABLED) == IN_IRQ | INTERRUPTS_ENABLED {
+            return Some(Violation::InterruptUnsafe { name: name, context: "an interrupt handler" });
+        }
+        if info.usage & (IN_TASKLET | THREAD_INTERRUPTS_ENABLED) == IN_TASKLET | THREAD_INTERRUPTS_ENABLED {
+            return Some(Violation::InterruptUnsafe { name: name, context: "a tasklet" });
+        }
+
+        // a lock that is only tried cannot deadlock, so it does not add dependencies
+        if !try_lock {
+            for index in 0..self.depth {
+                let held = self.held[index];
+                if held == id {
+                    return Some(Violation::Recursive(name));
+                }
+                if self.depends(id, held) {
+                    return Some(Violation::Inversion { held: self.name(held), taken: name });
+                }
+            }
+            for index in 0..self.depth {
+                self.after[self.held[index]] |= 1 << id;
+            }
+        }
+
+        if self.depth == MAX_HELD {
+            panic!("lockdep: more than {} locks held", MAX_HELD);
+        }
+        self.held[self.depth] = id;
+        self.depth += 1;
+        return None;
+    }
+
+    // Guards are not always dropped in order, the most recent acquisition of the class goes
+    fn release(&mut self, id: usize) {
+        if let Some(index) = self.held[..self.depth].iter().rposition(|&held| held == id) {
+            self.held.copy_within(index + 1..self.depth, index);
+            self.depth -= 1;
+        }
+    }
+}
+
+// `interrupts_enabled` is whether interrupts stay enabled while the lock is held
+pub fn acquire(class: &Class, name: &'static str, interrupts_enabled: bool, try_lock: bool) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut usage = if irq::in_irq() {
+        IN_IRQ
+    } else if deferred::in_tasklet() {
+        IN_TASKLET
+    } else if interrupts_enabled {
+        THREAD_INTERRUPTS_ENABLED
+    } else {
+        0
+    };
+    if interrupts_enabled {
+        usage |= INTERRUPTS_ENABLED;
+    }
+
+    let violation = without_interrupts(|| {
+        let mut state = STATE.lock();
+        let id = state.class(class, name);
+        return state.acquire(id, usage, try_lock);
+    });
+
+    // reported once, the panic path takes locks too
+    if let Some(violation) = violation {
+        disable();
+        match violation {
+            Violation::Recursive(name) => panic!("lockdep: {} taken while already held", name),
+            Violation::Inversion { held, taken } => {
+                panic!("lockdep: {} taken while holding {}, but it was taken before {} elsewhere", taken, held, held)
+            }
+            Violation::InterruptUnsafe { name, context } => {
+                panic!("lockdep: {} is taken in {} and held with interrupts enabled, use an IrqSpinLock", name, context)
+            }
+        }
+    }
+}
+
+pub fn release(class: &Class) {
+    let id = class.id.load(Ordering::Relaxed);
+    if id == 0 || DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    without_interrupts(|| STATE.lock().release(id - 1));
+}
+
+// Stops validating, for the panic path
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
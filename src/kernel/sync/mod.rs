@@ -0,0 +1,198 @@
+// Spinlocks. `IrqSpinLock` keeps interrupts disabled for as long as it is held, so it is the one
+// for data shared with interrupt handlers and tasklets. `SpinLock` leaves interrupts alone, it is
+// for data which is never touched at interrupt time (or only ever by tasklets).
+// With the `lock-debug` feature every acquisition goes through the lock validator, see `lockdep`.
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(feature = "lock-debug")]
+pub mod lockdep;
+
+pub struct IrqSpinLock<T> {
+    name: &'static str,
+    #[cfg(feature = "lock-debug")]
+    class: lockdep::Class,
+    inner: spin::Mutex<T>,
+}
+
+// Guards can be dropped in any order, interrupts are enabled again with the last one
+pub struct IrqSpinLockGuard<'a, T> {
+    #[cfg(feature = "lock-debug")]
+    class: &'a lockdep::Class,
+    inner: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+pub struct SpinLock<T> {
+    name: &'static str,
+    #[cfg(feature = "lock-debug")]
+    class: lockdep::Class,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    #[cfg(feature = "lock-debug")]
+    class: &'a lockdep::Class,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+// IrqSpinLocks held right now, and whether interrupts were enabled when the first of them was taken.
+// Both only change with interrupts disabled, there is a single CPU.
+static IRQ_LOCK_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static IRQ_LOCK_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+fn irq_lock_enter() {
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if IRQ_LOCK_DEPTH.fetch_add(1, Ordering::Relaxed) == 0 {
+        IRQ_LOCK_INTERRUPTS.store(interrupts_enabled, Ordering::Relaxed);
+    }
+}
+
+fn irq_lock_exit() {
+    if IRQ_LOCK_DEPTH.fetch_sub(1, Ordering::Relaxed) == 1 && IRQ_LOCK_INTERRUPTS.load(Ordering::Relaxed) {
+        interrupts::enable();
+    }
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> IrqSpinLock<T> {
+        return IrqSpinLock {
+            name: name,
+            #[cfg(feature = "lock-debug")]
+            class: lockdep::Class::new(),
+            inner: spin::Mutex::new(value),
+        };
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        irq_lock_enter();
+
+        #[cfg(feature = "lock-debug")]
+        lockdep::acquire(&self.class, self.name, false, false);
+
+        return IrqSpinLockGuard {
+            #[cfg(feature = "lock-debug")]
+            class: &self.class,
+            inner: ManuallyDrop::new(self.inner.lock()),
+        };
+    }
+
+    // For code which must not spin, like the panic and fault paths
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        irq_lock_enter();
+
+        let inner = match self.inner.try_lock() {
+            Some(inner) => inner,
+            None => {
+                irq_lock_exit();
+                return None;
+            }
+        };
+
+        #[cfg(feature = "lock-debug")]
+        lockdep::acquire(&self.class, self.name, false, true);
+
+        return Some(IrqSpinLockGuard {
+            #[cfg(feature = "lock-debug")]
+            class: &self.class,
+            inner: ManuallyDrop::new(inner),
+        });
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        return &self.inner;
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        return &mut self.inner;
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlocked before interrupts come back, an interrupt handler could be waiting for it
+        unsafe { ManuallyDrop::drop(&mut self.inner) };
+
+        #[cfg(feature = "lock-debug")]
+        lockdep::release(self.class);
+
+        irq_lock_exit();
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> SpinLock<T> {
+        return SpinLock {
+            name: name,
+            #[cfg(feature = "lock-debug")]
+            class: lockdep::Class::new(),
+            inner: spin::Mutex::new(value),
+        };
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        #[cfg(feature = "lock-debug")]
+        lockdep::acquire(&self.class, self.name, interrupts::are_enabled(), false);
+
+        return SpinLockGuard {
+            #[cfg(feature = "lock-debug")]
+            class: &self.class,
+            inner: self.inner.lock(),
+        };
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let inner = self.inner.try_lock()?;
+
+        #[cfg(feature = "lock-debug")]
+        lockdep::acquire(&self.class, self.name, interrupts::are_enabled(), true);
+
+        return Some(SpinLockGuard {
+            #[cfg(feature = "lock-debug")]
+            class: &self.class,
+            inner: inner,
+        });
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        return &self.inner;
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        return &mut self.inner;
+    }
+}
+
+#[cfg(feature = "lock-debug")]
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
+
+impl<T> fmt::Debug for IrqSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "IrqSpinLock({})", self.name);
+    }
+}
+
+impl<T> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "SpinLock({})", self.name);
+    }
+}
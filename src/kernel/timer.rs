@@ -1,5 +1,6 @@
 use core::arch::asm;
-use spin::Mutex;
+
+use crate::sync::IrqSpinLock;
 
 pub fn configure_pit() {
     assert_has_not_been_called!("PIT can be configured only once");
@@ -31,10 +32,10 @@ impl Timer {
     }
 }
 
-static TIMER: Mutex<Timer> = Mutex::new(Timer{time: 0});
+static TIMER: IrqSpinLock<Timer> = IrqSpinLock::new("timer", Timer{time: 0});
 
 pub fn pit_interrupt() {
-    x86_64::instructions::interrupts::without_interrupts(|| {TIMER.lock().increment()});
+    TIMER.lock().increment();
 }
 
 pub fn sleep(ms: u64) {
@@ -42,14 +43,16 @@ pub fn sleep(ms: u64) {
         return;
     }
 
-    let time = x86_64::instructions::interrupts::without_interrupts(|| {TIMER.lock().read() });
+    let time = TIMER.lock().read();
     let end_time = time + ms;
 
     loop {
-        let time = x86_64::instructions::interrupts::without_interrupts(|| {TIMER.lock().read() });
+        let time = TIMER.lock().read();
         if time >= end_time {
             return;
         }
+        // the kernel thread idles here, so queued work runs while it waits
+        crate::interrupts::deferred::run_work();
         x86_64::instructions::hlt();
     }
 }
@@ -57,3 +60,40 @@ pub fn sleep(ms: u64) {
 pub fn get_uptime() -> u64 {
     return TIMER.lock().read();
 }
+
+// Waits without interrupts, for the panic path. PIT channel 2 counts down in mode 0 with the gate
+// opened through port 0x61, and its output in bit 5 of the same port goes high at the end.
+pub fn busy_wait(ms: u64) {
+    use x86_64::instructions::port::Port;
+
+    const PIT_FREQUENCY: u64 = 1193182;
+    // the longest chunk whose count still fits in 16 bits
+    const CHUNK_MS: u64 = 50;
+
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+
+    let mut remaining = ms;
+    while remaining > 0 {
+        let chunk = core::cmp::min(remaining, CHUNK_MS);
+        let count = PIT_FREQUENCY * chunk / 1000;
+
+        unsafe {
+            // gate closed and speaker disconnected while the count is loaded
+            let value = control.read() & !0b11;
+            control.write(value);
+            command.write(0b1011_0000);
+            channel2.write(count as u8);
+            channel2.write((count >> 8) as u8);
+            control.write(value | 0b01);
+
+            while control.read() & (1 << 5) == 0 {
+                core::hint::spin_loop();
+            }
+            control.write(value);
+        }
+
+        remaining -= chunk;
+    }
+}